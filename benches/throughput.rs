@@ -0,0 +1,158 @@
+// Throughput of the tunnel data path versus the number of concurrent tunnels.
+//
+// Runs the proxy binary against a local sink target and pushes data through
+// 1 to 500 tunnels at once:
+//
+//     cargo bench --bench throughput
+//
+// Set TCP_LB_BIN to benchmark another build, e.g. one of an older commit, and
+// TCP_LB_BUFFER_SIZE and TCP_LB_FORWARDING to override the buffer_size and
+// forwarding mode of the tunnels.
+//
+// To compare against the baseline before the per tunnel byte counters, build
+// the parent of the commit that added this bench and point TCP_LB_BIN at it:
+//
+//     git worktree add /tmp/lb <parent commit>
+//     cargo build --release --manifest-path /tmp/lb/Cargo.toml
+//     TCP_LB_BIN=/tmp/lb/target/release/tcp_lb_rs cargo bench --bench throughput
+//
+// That build always forwards in 1 KiB chunks, it ignores buffer_size and
+// forwarding, so compare it with TCP_LB_BUFFER_SIZE=1024 as well.
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const TUNNEL_COUNTS: [usize; 5] = [1, 10, 50, 200, 500];
+const RUN_DURATION: Duration = Duration::from_secs(3);
+const CHUNK_SIZE: usize = 16 * 1024;
+
+fn free_local_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+// a target that reads and counts everything it receives
+async fn start_sink_target(received: Arc<AtomicU64>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let received = Arc::clone(&received);
+            tokio::spawn(async move {
+                let mut buf = vec![0; CHUNK_SIZE];
+                while let Ok(n) = stream.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    received.fetch_add(n as u64, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    addr
+}
+
+fn start_proxy(target_addr: SocketAddr, listen_addr: SocketAddr, api_addr: SocketAddr) -> Child {
+    let work_dir = std::env::temp_dir().join(format!("tcp_lb_bench_{}", std::process::id()));
+    std::fs::create_dir_all(&work_dir).unwrap();
+    let config_path = work_dir.join("lb-config.json");
+    let config = format!(
+        r#"{{
+            "lb_log": {{"log_set_level": "error"}},
+            "lb_node": {{"listen": "{}", "max_conn": 10000, "timeout": 60,
+                "enable_local_endpoints": false, "local_endpoints": [], "buffer_size": {},
+                "forwarding": "{}"}},
+            "lb_targets": [{{"target_endpoint": "{}", "target_max_conn": 10000,
+                "target_timeout": 60, "target_active": true}}],
+            "lb_api": {{"listen": "{}"}}
+        }}"#,
+        listen_addr,
+        std::env::var("TCP_LB_BUFFER_SIZE").unwrap_or_else(|_| "16384".to_string()),
+        std::env::var("TCP_LB_FORWARDING").unwrap_or_else(|_| "buffered".to_string()),
+        target_addr,
+        api_addr
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    let bin =
+        std::env::var("TCP_LB_BIN").unwrap_or_else(|_| env!("CARGO_BIN_EXE_tcp_lb_rs").to_string());
+    Command::new(bin)
+        .arg("--config")
+        .arg(&config_path)
+        .arg("--log-dir")
+        .arg(work_dir.join("log"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failure starting the proxy binary")
+}
+
+// push data through the tunnels for RUN_DURATION, return the bytes per second
+async fn run_tunnels(listen_addr: SocketAddr, count: usize, received: &AtomicU64) -> f64 {
+    let mut streams = Vec::with_capacity(count);
+    for _ in 0..count {
+        streams.push(TcpStream::connect(listen_addr).await.unwrap());
+    }
+    // let the proxy finish building the tunnels
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let running = Arc::new(AtomicBool::new(true));
+    let mut writers = Vec::with_capacity(count);
+    for mut stream in streams {
+        let running = Arc::clone(&running);
+        writers.push(tokio::spawn(async move {
+            let chunk = vec![0x5a; CHUNK_SIZE];
+            while running.load(Ordering::Relaxed) {
+                if stream.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+
+    let start_received = received.load(Ordering::Relaxed);
+    let start = Instant::now();
+    tokio::time::sleep(RUN_DURATION).await;
+    let bytes = received.load(Ordering::Relaxed) - start_received;
+    let elapsed = start.elapsed();
+
+    running.store(false, Ordering::Relaxed);
+    for writer in writers {
+        let _ = writer.await;
+    }
+    // let the proxy tear the tunnels down before the next round
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    bytes as f64 / elapsed.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    let received = Arc::new(AtomicU64::new(0));
+    let target_addr = start_sink_target(Arc::clone(&received)).await;
+    let listen_addr = free_local_addr();
+    let api_addr = free_local_addr();
+    let mut proxy = start_proxy(target_addr, listen_addr, api_addr);
+
+    // probe the api listener, a probe of the proxy listener would build a tunnel
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(api_addr).await.is_err() {
+        assert!(Instant::now() < deadline, "proxy did not start listening");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    println!("{:>8} {:>12}", "tunnels", "MiB/s");
+    for count in TUNNEL_COUNTS {
+        let rate = run_tunnels(listen_addr, count, &received).await;
+        println!("{:>8} {:>12.1}", count, rate / (1024.0 * 1024.0));
+    }
+
+    let _ = proxy.kill();
+    let _ = proxy.wait();
+}
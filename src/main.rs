@@ -3,40 +3,52 @@ extern crate lazy_static;
 extern crate tokio;
 
 mod proxy;
-use log::info;
+use log::{error, info};
 use proxy::api::start_api_server;
+use proxy::config::{format_config, load_config};
 use proxy::connection::start_maintain_loop;
+use proxy::health::start_health_check_loop;
 use proxy::proxy::start_tcp_proxy_server;
+use proxy::reload::start_reload_signal_loop;
 use proxy::target::init_targets_from_config;
 use std::ops::Deref;
 
 use fdlimit::raise_fd_limit;
 use flexi_logger::{detailed_format, Duplicate};
-use proxy::g::SERVER_INFO;
+use proxy::cli::USAGE;
+use proxy::g::{CLI_OPTIONS, SERVER_INFO};
 
 fn init_log() {
-    flexi_logger::Logger::with_str(
-        SERVER_INFO
-            .deref()
-            .server_config
-            .lb_log
-            .log_set_level
-            .clone(),
-    )
-    .log_to_file()
-    .directory("log")
-    .basename("tcp_lb_rs.log")
-    .duplicate_to_stdout(Duplicate::All)
-    .format_for_files(detailed_format)
-    .format_for_stdout(detailed_format)
-    .start()
-    .unwrap_or_else(|e| panic!("Logger initialization failed with {}", e));
+    flexi_logger::Logger::with_str(SERVER_INFO.deref().config().lb_log.log_set_level.clone())
+        .log_to_file()
+        .directory(CLI_OPTIONS.deref().log_dir.clone())
+        .basename("tcp_lb_rs.log")
+        .duplicate_to_stdout(Duplicate::All)
+        .format_for_files(detailed_format)
+        .format_for_stdout(detailed_format)
+        .start()
+        .unwrap_or_else(|e| panic!("Logger initialization failed with {}", e));
 }
 
-async fn run() {
-    // make sure config parameters valid
-    let _ = SERVER_INFO.deref().server_config.check();
+// validate the config file without touching the server state
+fn check_config() -> bool {
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    match config.check() {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
 
+async fn run() {
     // init log
     init_log();
 
@@ -59,23 +71,69 @@ async fn run() {
 
     let fut_tcp_proxy_server = start_tcp_proxy_server();
     info!(
-        "starting tcp proxy server, listen on [{}]...",
-        SERVER_INFO.deref().server_config.lb_node.listen.clone()
+        "starting tcp proxy server, listen on [{}], balance [{}]...",
+        SERVER_INFO.deref().config().lb_node.listen.clone(),
+        SERVER_INFO.deref().balancer.name()
     );
 
     let fut_api_server = start_api_server();
     info!(
         "starting api server, listen on [{}]... ",
-        SERVER_INFO.deref().server_config.lb_api.listen.clone()
+        SERVER_INFO.deref().config().lb_api.listen.clone()
     );
 
     let fut_maintain_loop = start_maintain_loop();
     info!("starting maintain loop...");
 
-    let (_, _, _) = tokio::join!(fut_tcp_proxy_server, fut_api_server, fut_maintain_loop);
+    let fut_health_check_loop = start_health_check_loop();
+    info!("starting health check loop...");
+
+    let fut_reload_signal_loop = start_reload_signal_loop();
+    info!("starting reload signal loop...");
+
+    // a listener that can not be set up at startup stops the whole server
+    if let Err(e) = tokio::try_join!(
+        fut_tcp_proxy_server,
+        fut_api_server,
+        fut_maintain_loop,
+        fut_health_check_loop,
+        fut_reload_signal_loop
+    ) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    if CLI_OPTIONS.deref().help {
+        println!("{}", USAGE);
+        return;
+    }
+    if CLI_OPTIONS.deref().version {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    if let Some(format) = CLI_OPTIONS.deref().convert_to {
+        match load_config().and_then(|config| format_config(&config, format)) {
+            Ok(config_str) => println!("{}", config_str),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // make sure config parameters valid before anything starts
+    if !check_config() {
+        std::process::exit(1);
+    }
+    if CLI_OPTIONS.deref().check_config {
+        println!("config ok");
+        return;
+    }
+
     run().await;
 }
@@ -6,11 +6,25 @@ use std::error::Error;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 
-use crate::proxy::connection::get_target_conn_count_by_target_id;
+use crate::proxy::config::{HealthCheckConfig, TargetConfig};
+use crate::proxy::connection::{
+    close_tunnel, close_tunnels, count_tunnels_by_target_id, get_targets_tunnel_stat,
+    NodeConnection, TargetConnection, TargetTunnelStat,
+};
 use crate::proxy::g::SERVER_INFO;
+use crate::proxy::outlier::OutlierState;
+use crate::proxy::proxy::bind_listener;
+use crate::proxy::reload::reload_config;
+use crate::proxy::target::{
+    add_target, drain_target, get_target_health_check, remove_target, set_target_health_check,
+    set_target_weight, undrain_target, update_target, Target,
+};
 use chrono::Utc;
+use log::{error, info};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
+use std::str::FromStr;
 use url::form_urlencoded;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -35,16 +49,31 @@ struct NodeInfoResp {
     pub listen: String,
     pub max_conn: u32,
     pub timeout: u32,
+    pub write_timeout_ms: u32,
+    pub max_tunnel_lifetime_secs: u32,
     pub conn_count: u32,
+    // closed tunnels counted by close reason since start
+    pub tunnel_close_reasons: HashMap<String, u64>,
 }
 
 impl NodeInfoResp {
-    pub fn new(_listen: String, _max_conn: u32, _timeout: u32, _conn_count: u32) -> NodeInfoResp {
+    pub fn new(
+        _listen: String,
+        _max_conn: u32,
+        _timeout: u32,
+        _write_timeout_ms: u32,
+        _max_tunnel_lifetime_secs: u32,
+        _conn_count: u32,
+        _tunnel_close_reasons: HashMap<String, u64>,
+    ) -> NodeInfoResp {
         NodeInfoResp {
             listen: _listen,
             max_conn: _max_conn,
             timeout: _timeout,
+            write_timeout_ms: _write_timeout_ms,
+            max_tunnel_lifetime_secs: _max_tunnel_lifetime_secs,
             conn_count: _conn_count,
+            tunnel_close_reasons: _tunnel_close_reasons,
         }
     }
 }
@@ -57,28 +86,63 @@ struct TargetInfoResp {
     pub timeout: u32,
     pub conn_count: u32,
     pub active: bool,
+    pub weight: u32,
+    pub backup: bool,
+    pub connect_latency_us: u64,
+    pub first_byte_latency_us: u64,
+    pub traffic_speed_1m: u64,
+    pub healthy: bool,
+    pub health_check: Option<HealthCheckConfig>,
+    pub health_success: u32,
+    pub health_failure: u32,
+    pub health_last_check: i64,
+    pub ejected: bool,
+    pub outlier: OutlierState,
+    pub slow_start_factor: f64,
+    pub draining: bool,
+    pub drain_deadline: i64,
 }
 
 impl TargetInfoResp {
-    pub fn new(
-        _target_id: String,
-        _endpoint: String,
-        _max_conn: u32,
-        _timeout: u32,
-        _conn_count: u32,
-        _active: bool,
-    ) -> TargetInfoResp {
+    pub fn new(_target_id: String, _target: &Target, _stat: &TargetTunnelStat) -> TargetInfoResp {
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
         TargetInfoResp {
             target_id: _target_id,
-            endpoint: _endpoint,
-            max_conn: _max_conn,
-            timeout: _timeout,
-            conn_count: _conn_count,
-            active: _active,
+            endpoint: _target.target_endpoint.clone(),
+            max_conn: _target.target_max_conn,
+            timeout: _target.target_timeout,
+            conn_count: _stat.conn_count,
+            active: _target.target_active,
+            weight: _target.target_weight,
+            backup: _target.target_backup,
+            connect_latency_us: _target.target_connect_latency,
+            first_byte_latency_us: _target.target_first_byte_latency,
+            traffic_speed_1m: _stat.traffic_speed_1m,
+            healthy: _target.target_status,
+            health_check: _target.target_health_check.clone(),
+            health_success: _target.target_health_success,
+            health_failure: _target.target_health_failure,
+            health_last_check: _target.target_health_last_check,
+            ejected: _target.target_outlier.is_ejected(now),
+            outlier: _target.target_outlier.clone(),
+            slow_start_factor: _target
+                .slow_start_factor(now, SERVER_INFO.deref().config().lb_node.slow_start_secs),
+            draining: _target.target_draining,
+            drain_deadline: _target.target_drain_deadline,
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DrainStatusResp {
+    pub target_id: String,
+    pub draining: bool,
+    pub drain_deadline: i64,
+    pub remaining_tunnels: u32,
+    // draining and no tunnel left, the target can be stopped safely
+    pub drained: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct NodeConnectionInfoResp {
     pub connect_id: String,
@@ -185,6 +249,174 @@ impl TunnelInfoResp {
             target_connection: _target_connection,
         }
     }
+
+    pub fn from_tunnel(
+        tunnel_id: &str,
+        node_connection: &NodeConnection,
+        target_connection: &TargetConnection,
+        now: i64,
+    ) -> TunnelInfoResp {
+        let node_speed = node_connection.connection.traffic.speed(now);
+        let target_speed = target_connection.connection.traffic.speed(now);
+        TunnelInfoResp::new(
+            tunnel_id.to_string(),
+            NodeConnectionInfoResp::new(
+                node_connection.connection.connect_id.clone(),
+                node_connection.connection.local_endpoint.clone(),
+                node_connection.connection.remote_endpoint.clone(),
+                node_connection.connection.create_time,
+                node_speed.read_speed_1m,
+                node_speed.read_speed_5m,
+                node_speed.read_speed_30m,
+                node_speed.write_speed_1m,
+                node_speed.write_speed_5m,
+                node_speed.write_speed_30m,
+            ),
+            TargetConnectionInfoResp::new(
+                target_connection.connection.connect_id.clone(),
+                target_connection.connection.local_endpoint.clone(),
+                target_connection.connection.remote_endpoint.clone(),
+                target_connection.connection.create_time,
+                target_speed.read_speed_1m,
+                target_speed.read_speed_5m,
+                target_speed.read_speed_30m,
+                target_speed.write_speed_1m,
+                target_speed.write_speed_5m,
+                target_speed.write_speed_30m,
+                target_connection.target_id.clone(),
+            ),
+        )
+    }
+}
+
+async fn parse_request_params(req: Request<Body>) -> Result<HashMap<String, String>, hyper::Error> {
+    // try to parse query string params from url
+    let mut params: HashMap<String, String> = req
+        .uri()
+        .query()
+        .map(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if params.is_empty() {
+        // try to parse query string params from body
+        let b = hyper::body::to_bytes(req).await?;
+        params = form_urlencoded::parse(b.as_ref())
+            .into_owned()
+            .collect::<HashMap<String, String>>();
+    }
+    Ok(params)
+}
+
+fn unprocessable_entity(msg: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNPROCESSABLE_ENTITY)
+        .body(msg.to_string().into())
+        .unwrap()
+}
+
+fn parse_param<T: FromStr>(
+    params: &HashMap<String, String>,
+    field: &str,
+) -> Result<Option<T>, String> {
+    match params.get(field) {
+        Some(v) => match v.parse() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(format!("Invalid field {}", field)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn target_config_from_params(params: &HashMap<String, String>) -> Result<TargetConfig, String> {
+    let lb_node = &SERVER_INFO.deref().config().lb_node;
+    Ok(TargetConfig {
+        target_endpoint: match params.get("target_endpoint") {
+            Some(target_endpoint) => target_endpoint.clone(),
+            None => return Err("Missing field".to_string()),
+        },
+        target_max_conn: parse_param(params, "target_max_conn")?.unwrap_or(lb_node.max_conn),
+        target_timeout: parse_param(params, "target_timeout")?.unwrap_or(lb_node.timeout),
+        target_active: parse_param(params, "target_active")?.unwrap_or(true),
+        target_weight: parse_param(params, "target_weight")?.unwrap_or(1),
+        target_backup: parse_param(params, "target_backup")?.unwrap_or(false),
+        target_health_check: None,
+    })
+}
+
+// an empty value clears an optional field
+fn parse_optional_param<T: FromStr>(
+    params: &HashMap<String, String>,
+    field: &str,
+) -> Result<Option<Option<T>>, String> {
+    match params.get(field) {
+        Some(v) if v.is_empty() => Ok(Some(None)),
+        Some(_) => Ok(Some(parse_param(params, field)?)),
+        None => Ok(None),
+    }
+}
+
+// overwrite the check fields given in the request
+fn health_check_from_params(
+    params: &HashMap<String, String>,
+    mut health_check: HealthCheckConfig,
+) -> Result<HealthCheckConfig, String> {
+    for (field, value) in [
+        ("check_type", &mut health_check.check_type),
+        ("http_method", &mut health_check.http_method),
+        ("http_path", &mut health_check.http_path),
+    ] {
+        if let Some(v) = parse_param(params, field)? {
+            *value = v;
+        }
+    }
+    for (field, value) in [
+        ("interval_ms", &mut health_check.interval_ms),
+        ("timeout_ms", &mut health_check.timeout_ms),
+        ("rise", &mut health_check.rise),
+        ("fall", &mut health_check.fall),
+        ("read_timeout_ms", &mut health_check.read_timeout_ms),
+    ] {
+        if let Some(v) = parse_param(params, field)? {
+            *value = v;
+        }
+    }
+    for (field, value) in [
+        ("send", &mut health_check.send),
+        ("send_hex", &mut health_check.send_hex),
+        ("expect", &mut health_check.expect),
+        ("expect_regex", &mut health_check.expect_regex),
+        ("http_host", &mut health_check.http_host),
+        ("http_expect_body", &mut health_check.http_expect_body),
+    ] {
+        if let Some(v) = parse_optional_param(params, field)? {
+            *value = v;
+        }
+    }
+    if let Some(port) = parse_optional_param(params, "port")? {
+        health_check.port = port;
+    }
+    if let Some(v) = params.get("http_expect_status") {
+        // a comma separated list like 200,204
+        health_check.http_expect_status = v
+            .split(',')
+            .map(|status| status.trim().parse())
+            .collect::<Result<Vec<u16>, _>>()
+            .map_err(|_| "Invalid field http_expect_status".to_string())?;
+    }
+    if let Some(tls_skip_verify) = parse_param(params, "tls_skip_verify")? {
+        health_check.tls_skip_verify = tls_skip_verify;
+    }
+    Ok(health_check)
+}
+
+fn ok_resp() -> Response<Body> {
+    let json_resp = JsonResp::new(1, true, None);
+    let ret_str = serde_json::to_string(&json_resp).unwrap();
+    Response::new(Body::from(ret_str))
 }
 
 async fn request_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
@@ -195,11 +427,20 @@ async fn request_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Er
         }
 
         (&Method::GET, "/api/get_node_info") | (&Method::POST, "/api/get_node_info") => {
+            let server_config = SERVER_INFO.deref().config();
             let node_info_resp = NodeInfoResp::new(
-                SERVER_INFO.deref().server_config.lb_node.listen.clone(),
-                SERVER_INFO.deref().server_config.lb_node.max_conn.clone(),
-                SERVER_INFO.deref().server_config.lb_node.timeout.clone(),
+                server_config.lb_node.listen.clone(),
+                server_config.lb_node.max_conn,
+                server_config.lb_node.timeout,
+                server_config.lb_node.write_timeout_ms,
+                server_config.lb_node.max_tunnel_lifetime_secs,
                 SERVER_INFO.deref().tunnel_info.lock().await.len() as u32,
+                SERVER_INFO
+                    .deref()
+                    .tunnel_close_stats
+                    .lock()
+                    .unwrap()
+                    .clone(),
             );
             let json_resp = JsonResp::new(1, node_info_resp, None);
             let ret_str = serde_json::to_string(&json_resp).unwrap();
@@ -208,14 +449,12 @@ async fn request_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Er
 
         (&Method::GET, "/api/get_targets_info") | (&Method::POST, "/api/get_targets_info") => {
             let mut targets_info_resp = vec![];
+            let targets_stat = get_targets_tunnel_stat().await;
             for (k, target) in SERVER_INFO.deref().targets_info.lock().await.iter() {
                 let target_info_resp = TargetInfoResp::new(
                     k.clone(),
-                    target.target_endpoint.clone(),
-                    target.target_max_conn.clone(),
-                    target.target_timeout.clone(),
-                    get_target_conn_count_by_target_id(k.clone()).await,
-                    target.target_active.clone(),
+                    target,
+                    &targets_stat.get(k).cloned().unwrap_or_default(),
                 );
                 targets_info_resp.push(target_info_resp.clone());
             }
@@ -224,107 +463,276 @@ async fn request_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Er
             Ok(Response::new(Body::from(ret_str)))
         }
 
-        (&Method::GET, "/api/get_target_tunnel_info")
-        | (&Method::POST, "/api/get_target_tunnel_info") => {
-            // try to parse query string params from url
-            let mut params = req
-                .uri()
-                .query()
-                .map(|v| {
-                    url::form_urlencoded::parse(v.as_bytes())
-                        .into_owned()
-                        .collect()
-                })
-                .unwrap_or_else(HashMap::new);
-
-            if params.len() == 0 {
-                // try to parse query string params from body
-                let b = hyper::body::to_bytes(req).await?;
-                params = form_urlencoded::parse(b.as_ref())
-                    .into_owned()
-                    .collect::<HashMap<String, String>>();
+        (&Method::GET, "/api/add_target") | (&Method::POST, "/api/add_target") => {
+            let params = parse_request_params(req).await?;
+
+            let target_config = match target_config_from_params(&params) {
+                Ok(target_config) => target_config,
+                Err(e) => return Ok(unprocessable_entity(&e)),
+            };
+
+            let target_id = match add_target(&target_config).await {
+                Ok(target_id) => target_id,
+                Err(e) => return Ok(unprocessable_entity(&e)),
+            };
+            info!(
+                "add target |{}| [{}]",
+                target_id, target_config.target_endpoint
+            );
+
+            let json_resp = JsonResp::new(1, target_id, None);
+            let ret_str = serde_json::to_string(&json_resp).unwrap();
+            Ok(Response::new(Body::from(ret_str)))
+        }
+
+        (&Method::GET, "/api/delete_target") | (&Method::POST, "/api/delete_target") => {
+            let params = parse_request_params(req).await?;
+
+            let target_id = match params.get("target_id") {
+                Some(target_id) => target_id.clone(),
+                None => return Ok(unprocessable_entity("Missing field")),
+            };
+            let target = match remove_target(&target_id).await {
+                Some(target) => target,
+                None => return Ok(unprocessable_entity("Target not found")),
+            };
+            info!("delete target |{}| [{}]", target_id, target.target_endpoint);
+
+            Ok(ok_resp())
+        }
+
+        (&Method::GET, "/api/update_target") | (&Method::POST, "/api/update_target") => {
+            let params = parse_request_params(req).await?;
+
+            let target_id = match params.get("target_id") {
+                Some(target_id) => target_id.clone(),
+                None => return Ok(unprocessable_entity("Missing field")),
+            };
+            let target_max_conn = match parse_param(&params, "target_max_conn") {
+                Ok(target_max_conn) => target_max_conn,
+                Err(e) => return Ok(unprocessable_entity(&e)),
+            };
+            let target_timeout = match parse_param(&params, "target_timeout") {
+                Ok(target_timeout) => target_timeout,
+                Err(e) => return Ok(unprocessable_entity(&e)),
+            };
+            let target_active = match parse_param(&params, "target_active") {
+                Ok(target_active) => target_active,
+                Err(e) => return Ok(unprocessable_entity(&e)),
+            };
+
+            if let Err(e) =
+                update_target(&target_id, target_max_conn, target_timeout, target_active).await
+            {
+                return Ok(unprocessable_entity(&e));
             }
+            info!(
+                "update target |{}|, max_conn: {:?}, timeout: {:?}, active: {:?}",
+                target_id, target_max_conn, target_timeout, target_active
+            );
+
+            Ok(ok_resp())
+        }
+
+        (&Method::GET, "/api/drain_target") | (&Method::POST, "/api/drain_target") => {
+            let params = parse_request_params(req).await?;
+
+            let target_id = match params.get("target_id") {
+                Some(target_id) => target_id.clone(),
+                None => return Ok(unprocessable_entity("Missing field")),
+            };
+            let enable = match parse_param(&params, "enable") {
+                Ok(enable) => enable.unwrap_or(true),
+                Err(e) => return Ok(unprocessable_entity(&e)),
+            };
+            let deadline_secs: u32 = match parse_param(&params, "deadline_secs") {
+                Ok(deadline_secs) => deadline_secs.unwrap_or(0),
+                Err(e) => return Ok(unprocessable_entity(&e)),
+            };
 
-            let target_id = if let Some(target_id) = params.get("target_id") {
-                target_id
+            let found = if enable {
+                drain_target(&target_id, deadline_secs).await
             } else {
-                return Ok(Response::builder()
-                    .status(StatusCode::UNPROCESSABLE_ENTITY)
-                    .body("Missing field".into())
-                    .unwrap());
+                undrain_target(&target_id).await
             };
+            if !found {
+                return Ok(unprocessable_entity("Target not found"));
+            }
+            info!(
+                "set target |{}| draining: {}, deadline_secs: {}",
+                target_id, enable, deadline_secs
+            );
+
+            Ok(ok_resp())
+        }
+
+        (&Method::GET, "/api/drain_status") | (&Method::POST, "/api/drain_status") => {
+            let params = parse_request_params(req).await?;
 
+            let target_id = match params.get("target_id") {
+                Some(target_id) => target_id.clone(),
+                None => return Ok(unprocessable_entity("Missing field")),
+            };
+            let (draining, drain_deadline) = match SERVER_INFO
+                .deref()
+                .targets_info
+                .lock()
+                .await
+                .get(&target_id)
+            {
+                Some(target) => (target.target_draining, target.target_drain_deadline),
+                None => return Ok(unprocessable_entity("Target not found")),
+            };
+            let remaining_tunnels = count_tunnels_by_target_id(&target_id).await;
+
+            let drain_status_resp = DrainStatusResp {
+                target_id,
+                draining,
+                drain_deadline,
+                remaining_tunnels,
+                drained: draining && remaining_tunnels == 0,
+            };
+            let json_resp = JsonResp::new(1, drain_status_resp, None);
+            let ret_str = serde_json::to_string(&json_resp).unwrap();
+            Ok(Response::new(Body::from(ret_str)))
+        }
+
+        (&Method::GET, "/api/kill_tunnel") | (&Method::POST, "/api/kill_tunnel") => {
+            let params = parse_request_params(req).await?;
+
+            let closed = if let Some(tunnel_id) = params.get("tunnel_id") {
+                if !close_tunnel(tunnel_id, "killed by api").await {
+                    return Ok(unprocessable_entity("Tunnel not found"));
+                }
+                vec![tunnel_id.clone()]
+            } else if let Some(client_ip) = params.get("client_ip") {
+                let client_ip: IpAddr = match client_ip.parse() {
+                    Ok(client_ip) => client_ip,
+                    Err(_) => return Ok(unprocessable_entity("Invalid field client_ip")),
+                };
+                close_tunnels(
+                    |node_info, _| {
+                        node_info
+                            .connection
+                            .remote_endpoint
+                            .parse::<SocketAddr>()
+                            .is_ok_and(|addr| addr.ip() == client_ip)
+                    },
+                    "killed by api",
+                )
+                .await
+            } else if let Some(target_id) = params.get("target_id") {
+                close_tunnels(
+                    |_, target_info| &target_info.target_id == target_id,
+                    "killed by api",
+                )
+                .await
+            } else {
+                return Ok(unprocessable_entity("Missing field"));
+            };
+            info!("kill tunnels {:?}", closed);
+
+            let json_resp = JsonResp::new(1, closed, None);
+            let ret_str = serde_json::to_string(&json_resp).unwrap();
+            Ok(Response::new(Body::from(ret_str)))
+        }
+
+        (&Method::GET, "/api/reload_config") | (&Method::POST, "/api/reload_config") => {
+            let result = match reload_config().await {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("config reload failed, keep the running config: {}", e);
+                    return Ok(unprocessable_entity(&e));
+                }
+            };
+
+            let json_resp = JsonResp::new(1, result, None);
+            let ret_str = serde_json::to_string(&json_resp).unwrap();
+            Ok(Response::new(Body::from(ret_str)))
+        }
+
+        (&Method::GET, "/api/set_target_weight") | (&Method::POST, "/api/set_target_weight") => {
+            let params = parse_request_params(req).await?;
+
+            let target_id = match params.get("target_id") {
+                Some(target_id) => target_id.clone(),
+                None => return Ok(unprocessable_entity("Missing field")),
+            };
+            let weight: u32 = match params.get("weight").map(|w| w.parse()) {
+                Some(Ok(weight)) => weight,
+                Some(Err(_)) => return Ok(unprocessable_entity("Invalid field weight")),
+                None => return Ok(unprocessable_entity("Missing field")),
+            };
+
+            if !set_target_weight(target_id.clone(), weight).await {
+                return Ok(unprocessable_entity("Target not found"));
+            }
+            info!("set target |{}| weight to {}", target_id, weight);
+
+            let json_resp = JsonResp::new(1, true, None);
+            let ret_str = serde_json::to_string(&json_resp).unwrap();
+            Ok(Response::new(Body::from(ret_str)))
+        }
+
+        (&Method::GET, "/api/set_target_health_check")
+        | (&Method::POST, "/api/set_target_health_check") => {
+            let params = parse_request_params(req).await?;
+
+            let target_id = match params.get("target_id") {
+                Some(target_id) => target_id.clone(),
+                None => return Ok(unprocessable_entity("Missing field")),
+            };
+
+            let current_health_check = match get_target_health_check(&target_id).await {
+                Some(current_health_check) => current_health_check,
+                None => return Ok(unprocessable_entity("Target not found")),
+            };
+            let health_check = match params.get("enable").map(|v| v.as_str()) {
+                Some("false") => None,
+                Some("true") | None => {
+                    // fields missing from the request keep their current value
+                    let health_check = match health_check_from_params(
+                        &params,
+                        current_health_check.unwrap_or_default(),
+                    ) {
+                        Ok(health_check) => health_check,
+                        Err(e) => return Ok(unprocessable_entity(&e)),
+                    };
+                    if let Err(e) = health_check.check() {
+                        return Ok(unprocessable_entity(&e));
+                    }
+                    Some(health_check)
+                }
+                Some(_) => return Ok(unprocessable_entity("Invalid field enable")),
+            };
+
+            if !set_target_health_check(target_id.clone(), health_check.clone()).await {
+                return Ok(unprocessable_entity("Target not found"));
+            }
+            info!(
+                "set target |{}| health check to {:?}",
+                target_id, health_check
+            );
+
+            let json_resp = JsonResp::new(1, true, None);
+            let ret_str = serde_json::to_string(&json_resp).unwrap();
+            Ok(Response::new(Body::from(ret_str)))
+        }
+
+        (&Method::GET, "/api/get_target_tunnel_info")
+        | (&Method::POST, "/api/get_target_tunnel_info") => {
+            let params = parse_request_params(req).await?;
+
+            let target_id = match params.get("target_id") {
+                Some(target_id) => target_id,
+                None => return Ok(unprocessable_entity("Missing field")),
+            };
+
+            let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
             let mut target_tunnel_info = vec![];
             for (k, v) in SERVER_INFO.deref().tunnel_info.lock().await.iter() {
                 if target_id.deref() == v.1.target_id {
-                    let (node_connection, target_connection) = v.clone();
-                    let node_connection_resp = NodeConnectionInfoResp::new(
-                        node_connection.connection.connect_id.clone(),
-                        node_connection.connection.local_endpoint.clone(),
-                        node_connection.connection.remote_endpoint.clone(),
-                        node_connection.connection.create_time.clone(),
-                        node_connection.connection.read_bytes_1m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - node_connection.connection.start_time_1m)
-                                as u64,
-                        node_connection.connection.read_bytes_5m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - node_connection.connection.start_time_5m)
-                                as u64,
-                        node_connection.connection.read_bytes_30m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - node_connection.connection.start_time_30m)
-                                as u64,
-                        node_connection.connection.write_bytes_1m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - node_connection.connection.start_time_1m)
-                                as u64,
-                        node_connection.connection.write_bytes_5m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - node_connection.connection.start_time_5m)
-                                as u64,
-                        node_connection.connection.write_bytes_30m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - node_connection.connection.start_time_30m)
-                                as u64,
-                    );
-                    let target_connection_resp = TargetConnectionInfoResp::new(
-                        target_connection.connection.connect_id.clone(),
-                        target_connection.connection.local_endpoint.clone(),
-                        target_connection.connection.remote_endpoint.clone(),
-                        target_connection.connection.create_time.clone(),
-                        target_connection.connection.read_bytes_1m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - target_connection.connection.start_time_1m)
-                                as u64,
-                        target_connection.connection.read_bytes_5m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - target_connection.connection.start_time_5m)
-                                as u64,
-                        target_connection.connection.read_bytes_30m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - target_connection.connection.start_time_30m)
-                                as u64,
-                        target_connection.connection.write_bytes_1m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - target_connection.connection.start_time_1m)
-                                as u64,
-                        target_connection.connection.write_bytes_5m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - target_connection.connection.start_time_5m)
-                                as u64,
-                        target_connection.connection.write_bytes_30m * 8 as u64 * 1000000000 as u64
-                            / (Utc::now().timestamp_nanos()
-                                - target_connection.connection.start_time_30m)
-                                as u64,
-                        v.1.target_id.clone(),
-                    );
-                    let tunnel_info_resp = TunnelInfoResp::new(
-                        k.clone(),
-                        node_connection_resp,
-                        target_connection_resp,
-                    );
-                    target_tunnel_info.push(tunnel_info_resp);
+                    target_tunnel_info.push(TunnelInfoResp::from_tunnel(k, &v.0, &v.1, now));
                 }
             }
 
@@ -334,67 +742,10 @@ async fn request_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Er
         }
 
         (&Method::GET, "/api/get_tunnel_info") | (&Method::POST, "/api/get_tunnel_info") => {
+            let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
             let mut target_tunnel_info = vec![];
             for (k, v) in SERVER_INFO.deref().tunnel_info.lock().await.iter() {
-                let (node_connection, target_connection) = v.clone();
-                let node_connection_resp = NodeConnectionInfoResp::new(
-                    node_connection.connection.connect_id.clone(),
-                    node_connection.connection.local_endpoint.clone(),
-                    node_connection.connection.remote_endpoint.clone(),
-                    node_connection.connection.create_time.clone(),
-                    node_connection.connection.read_bytes_1m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos() - node_connection.connection.start_time_1m)
-                            as u64,
-                    node_connection.connection.read_bytes_5m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos() - node_connection.connection.start_time_5m)
-                            as u64,
-                    node_connection.connection.read_bytes_30m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos() - node_connection.connection.start_time_30m)
-                            as u64,
-                    node_connection.connection.write_bytes_1m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos() - node_connection.connection.start_time_1m)
-                            as u64,
-                    node_connection.connection.write_bytes_5m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos() - node_connection.connection.start_time_5m)
-                            as u64,
-                    node_connection.connection.write_bytes_30m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos() - node_connection.connection.start_time_30m)
-                            as u64,
-                );
-                let target_connection_resp = TargetConnectionInfoResp::new(
-                    target_connection.connection.connect_id.clone(),
-                    target_connection.connection.local_endpoint.clone(),
-                    target_connection.connection.remote_endpoint.clone(),
-                    target_connection.connection.create_time.clone(),
-                    target_connection.connection.read_bytes_1m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos()
-                            - target_connection.connection.start_time_1m)
-                            as u64,
-                    target_connection.connection.read_bytes_5m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos()
-                            - target_connection.connection.start_time_5m)
-                            as u64,
-                    target_connection.connection.read_bytes_30m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos()
-                            - target_connection.connection.start_time_30m)
-                            as u64,
-                    target_connection.connection.write_bytes_1m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos()
-                            - target_connection.connection.start_time_1m)
-                            as u64,
-                    target_connection.connection.write_bytes_5m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos()
-                            - target_connection.connection.start_time_5m)
-                            as u64,
-                    target_connection.connection.write_bytes_30m * 8 as u64 * 1000000000 as u64
-                        / (Utc::now().timestamp_nanos()
-                            - target_connection.connection.start_time_30m)
-                            as u64,
-                    v.1.target_id.clone(),
-                );
-                let tunnel_info_resp =
-                    TunnelInfoResp::new(k.clone(), node_connection_resp, target_connection_resp);
-                target_tunnel_info.push(tunnel_info_resp);
+                target_tunnel_info.push(TunnelInfoResp::from_tunnel(k, &v.0, &v.1, now));
             }
 
             let json_resp = JsonResp::new(1, target_tunnel_info, None);
@@ -412,15 +763,10 @@ async fn request_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Er
 }
 
 pub async fn start_api_server() -> Result<(), Box<dyn Error>> {
-    let addr = SERVER_INFO
-        .deref()
-        .server_config
-        .lb_api
-        .listen
-        .clone()
-        .parse()?;
+    let addr = SERVER_INFO.deref().config().lb_api.listen.clone().parse()?;
     let service = make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(request_handler)) });
-    let server = Server::bind(&addr).serve(service);
+    // an ipv6 api address is dual-stack as well
+    let server = Server::from_tcp(bind_listener(addr, false)?)?.serve(service);
     server.await?;
     Ok(())
 }
@@ -0,0 +1,561 @@
+use md5;
+
+use crate::proxy::config::NodeConfig;
+use crate::proxy::target::{calc_target_id_by_endpoint, TargetDump};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+pub const BALANCE_LEAST_CONN: &str = "least_conn";
+pub const BALANCE_WEIGHTED_ROUND_ROBIN: &str = "weighted_round_robin";
+pub const BALANCE_CONSISTENT_HASH: &str = "consistent_hash";
+pub const BALANCE_RANDOM: &str = "random";
+pub const BALANCE_P2C_LEAST_CONN: &str = "p2c_least_conn";
+pub const BALANCE_LEAST_LATENCY: &str = "least_latency";
+pub const BALANCE_LEAST_TRAFFIC: &str = "least_traffic";
+pub const BALANCE_STRATEGIES: [&str; 7] = [
+    BALANCE_LEAST_CONN,
+    BALANCE_WEIGHTED_ROUND_ROBIN,
+    BALANCE_CONSISTENT_HASH,
+    BALANCE_RANDOM,
+    BALANCE_P2C_LEAST_CONN,
+    BALANCE_LEAST_LATENCY,
+    BALANCE_LEAST_TRAFFIC,
+];
+
+pub const HASH_KEY_IP: &str = "ip";
+pub const HASH_KEY_IP_PORT: &str = "ip_port";
+
+// virtual nodes per target on the hash ring
+const HASH_RING_REPLICAS: u32 = 160;
+
+pub trait Balancer: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // order the eligible targets by preference, the accept loop tries them in turn
+    fn select(&self, targets: Vec<TargetDump>, client_addr: &SocketAddr) -> Vec<TargetDump>;
+
+    // called with the ids of all configured targets whenever they are added or removed
+    fn update_targets(&self, _target_ids: Vec<String>) {}
+
+    // whether the strategy can pick the target at all, checked before the backup tier is chosen
+    fn is_selectable(&self, _target: &TargetDump) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LeastConnBalancer {}
+
+impl LeastConnBalancer {
+    pub fn new() -> LeastConnBalancer {
+        LeastConnBalancer {}
+    }
+}
+
+impl Balancer for LeastConnBalancer {
+    fn name(&self) -> &'static str {
+        BALANCE_LEAST_CONN
+    }
+
+    fn select(&self, mut targets: Vec<TargetDump>, _client_addr: &SocketAddr) -> Vec<TargetDump> {
+        targets.sort_by_key(|t| t.target_conn_count);
+        targets
+    }
+}
+
+// smooth weighted round-robin, the same algorithm as nginx upstream
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobinBalancer {
+    current_weights: Mutex<HashMap<String, i64>>,
+}
+
+impl WeightedRoundRobinBalancer {
+    pub fn new() -> WeightedRoundRobinBalancer {
+        WeightedRoundRobinBalancer {
+            current_weights: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Balancer for WeightedRoundRobinBalancer {
+    fn name(&self) -> &'static str {
+        BALANCE_WEIGHTED_ROUND_ROBIN
+    }
+
+    fn select(&self, targets: Vec<TargetDump>, _client_addr: &SocketAddr) -> Vec<TargetDump> {
+        let mut targets: Vec<(String, TargetDump)> = targets
+            .into_iter()
+            .filter(|t| self.is_selectable(t))
+            .map(|t| {
+                (
+                    calc_target_id_by_endpoint(t.target.target_endpoint.clone()),
+                    t,
+                )
+            })
+            .collect();
+        if targets.is_empty() {
+            return vec![];
+        }
+
+        let mut current_weights = self.current_weights.lock().unwrap();
+        // forget the targets which are gone or no longer eligible
+        current_weights.retain(|k, _| targets.iter().any(|(id, _)| id == k));
+
+        let mut total_weight: i64 = 0;
+        for (id, t) in targets.iter() {
+            let weight = t.target.target_weight as i64;
+            *current_weights.entry(id.clone()).or_insert(0) += weight;
+            total_weight += weight;
+        }
+
+        // the target with the highest current weight is tried first, the rest are the fallback
+        targets.sort_by_key(|(id, _)| std::cmp::Reverse(current_weights[id]));
+        if let Some(w) = current_weights.get_mut(&targets[0].0) {
+            *w -= total_weight;
+        }
+        targets.into_iter().map(|(_, t)| t).collect()
+    }
+
+    // targets with zero weight never take new connections
+    fn is_selectable(&self, target: &TargetDump) -> bool {
+        target.target.target_weight > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashKey {
+    Ip,
+    IpPort,
+}
+
+impl HashKey {
+    pub fn parse(hash_key: &str) -> Result<HashKey, String> {
+        match hash_key {
+            HASH_KEY_IP => Ok(HashKey::Ip),
+            HASH_KEY_IP_PORT => Ok(HashKey::IpPort),
+            _ => Err(format!("Invalid hash key [{}]", hash_key)),
+        }
+    }
+}
+
+fn hash_u64(key: &str) -> u64 {
+    let digest = md5::compute(key.as_bytes());
+    u64::from_be_bytes(digest.0[0..8].try_into().unwrap())
+}
+
+#[derive(Debug, Default)]
+struct HashRing {
+    target_ids: Vec<String>,
+    points: Vec<(u64, String)>,
+}
+
+impl HashRing {
+    fn new(mut target_ids: Vec<String>) -> HashRing {
+        target_ids.sort();
+        let mut points = Vec::with_capacity(target_ids.len() * HASH_RING_REPLICAS as usize);
+        for id in target_ids.iter() {
+            for i in 0..HASH_RING_REPLICAS {
+                points.push((hash_u64(format!("{}-{}", id, i).as_str()), id.clone()));
+            }
+        }
+        points.sort();
+        HashRing { target_ids, points }
+    }
+
+    // walk the ring clockwise from the key and take the eligible targets in the order they
+    // are met, each target is taken once
+    fn lookup(&self, key: u64, eligible: &mut HashMap<String, TargetDump>) -> Vec<TargetDump> {
+        let mut ordered: Vec<TargetDump> = Vec::with_capacity(eligible.len());
+        let mut seen: HashSet<&str> = HashSet::with_capacity(self.target_ids.len());
+        let start = self.points.partition_point(|(h, _)| *h < key);
+        for i in 0..self.points.len() {
+            if eligible.is_empty() || seen.len() == self.target_ids.len() {
+                break;
+            }
+            let (_, id) = &self.points[(start + i) % self.points.len()];
+            if seen.insert(id.as_str()) {
+                if let Some(t) = eligible.remove(id) {
+                    ordered.push(t);
+                }
+            }
+        }
+        ordered
+    }
+}
+
+// consistent hashing on the client address, so a client sticks to the same target. the ring
+// holds all configured targets, an ineligible target is skipped and its clients go to the next
+// target on the ring until it is back
+#[derive(Debug)]
+pub struct ConsistentHashBalancer {
+    hash_key: HashKey,
+    ring: Mutex<HashRing>,
+}
+
+impl ConsistentHashBalancer {
+    pub fn new(hash_key: HashKey) -> ConsistentHashBalancer {
+        ConsistentHashBalancer {
+            hash_key,
+            ring: Mutex::new(HashRing::default()),
+        }
+    }
+
+    fn client_key(&self, client_addr: &SocketAddr) -> String {
+        match self.hash_key {
+            HashKey::Ip => client_addr.ip().to_string(),
+            HashKey::IpPort => client_addr.to_string(),
+        }
+    }
+}
+
+impl Balancer for ConsistentHashBalancer {
+    fn name(&self) -> &'static str {
+        BALANCE_CONSISTENT_HASH
+    }
+
+    fn select(&self, targets: Vec<TargetDump>, client_addr: &SocketAddr) -> Vec<TargetDump> {
+        let mut targets: HashMap<String, TargetDump> = targets
+            .into_iter()
+            .map(|t| {
+                (
+                    calc_target_id_by_endpoint(t.target.target_endpoint.clone()),
+                    t,
+                )
+            })
+            .collect();
+
+        let mut ordered = self.ring.lock().unwrap().lookup(
+            hash_u64(self.client_key(client_addr).as_str()),
+            &mut targets,
+        );
+        // targets not on the ring yet go last
+        let mut rest: Vec<(String, TargetDump)> = targets.into_iter().collect();
+        rest.sort_by(|(l, _), (r, _)| l.cmp(r));
+        ordered.extend(rest.into_iter().map(|(_, t)| t));
+        ordered
+    }
+
+    fn update_targets(&self, mut target_ids: Vec<String>) {
+        target_ids.sort();
+        let mut ring = self.ring.lock().unwrap();
+        if ring.target_ids != target_ids {
+            *ring = HashRing::new(target_ids);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RandomBalancer {}
+
+impl RandomBalancer {
+    pub fn new() -> RandomBalancer {
+        RandomBalancer {}
+    }
+}
+
+impl Balancer for RandomBalancer {
+    fn name(&self) -> &'static str {
+        BALANCE_RANDOM
+    }
+
+    fn select(&self, mut targets: Vec<TargetDump>, _client_addr: &SocketAddr) -> Vec<TargetDump> {
+        targets.shuffle(&mut rand::thread_rng());
+        targets
+    }
+}
+
+// power of two choices: sample two targets and prefer the one with fewer connections
+#[derive(Debug, Default)]
+pub struct P2cLeastConnBalancer {}
+
+impl P2cLeastConnBalancer {
+    pub fn new() -> P2cLeastConnBalancer {
+        P2cLeastConnBalancer {}
+    }
+}
+
+impl Balancer for P2cLeastConnBalancer {
+    fn name(&self) -> &'static str {
+        BALANCE_P2C_LEAST_CONN
+    }
+
+    fn select(&self, mut targets: Vec<TargetDump>, _client_addr: &SocketAddr) -> Vec<TargetDump> {
+        if targets.len() < 2 {
+            return targets;
+        }
+        let mut rng = rand::thread_rng();
+        let i = rng.gen_range(0..targets.len());
+        let mut j = rng.gen_range(0..targets.len() - 1);
+        if j >= i {
+            j += 1;
+        }
+        let (first, second) = if targets[j].target_conn_count < targets[i].target_conn_count {
+            (j, i)
+        } else {
+            (i, j)
+        };
+
+        // the two choices go first, the remaining targets are a random fallback
+        let mut ordered = Vec::with_capacity(targets.len());
+        ordered.push(targets[first].clone());
+        ordered.push(targets[second].clone());
+        let mut rest: Vec<TargetDump> = targets
+            .drain(..)
+            .enumerate()
+            .filter(|(k, _)| *k != first && *k != second)
+            .map(|(_, t)| t)
+            .collect();
+        rest.shuffle(&mut rng);
+        ordered.append(&mut rest);
+        ordered
+    }
+}
+
+// prefer the targets with the lowest measured connect latency, unmeasured targets go first
+// so that every target gets sampled
+#[derive(Debug, Default)]
+pub struct LeastLatencyBalancer {}
+
+impl LeastLatencyBalancer {
+    pub fn new() -> LeastLatencyBalancer {
+        LeastLatencyBalancer {}
+    }
+}
+
+impl Balancer for LeastLatencyBalancer {
+    fn name(&self) -> &'static str {
+        BALANCE_LEAST_LATENCY
+    }
+
+    fn select(&self, mut targets: Vec<TargetDump>, _client_addr: &SocketAddr) -> Vec<TargetDump> {
+        targets.sort_by_key(|t| (t.target.target_connect_latency, t.target_conn_count));
+        targets
+    }
+}
+
+// prefer the targets moving the fewest bytes over the last minute
+#[derive(Debug, Default)]
+pub struct LeastTrafficBalancer {}
+
+impl LeastTrafficBalancer {
+    pub fn new() -> LeastTrafficBalancer {
+        LeastTrafficBalancer {}
+    }
+}
+
+impl Balancer for LeastTrafficBalancer {
+    fn name(&self) -> &'static str {
+        BALANCE_LEAST_TRAFFIC
+    }
+
+    fn select(&self, mut targets: Vec<TargetDump>, _client_addr: &SocketAddr) -> Vec<TargetDump> {
+        targets.sort_by_key(|t| (t.target_traffic_speed, t.target_conn_count));
+        targets
+    }
+}
+
+pub fn new_balancer(lb_node: &NodeConfig) -> Result<Box<dyn Balancer>, String> {
+    match lb_node.balance.as_str() {
+        BALANCE_LEAST_CONN => Ok(Box::new(LeastConnBalancer::new())),
+        BALANCE_WEIGHTED_ROUND_ROBIN => Ok(Box::new(WeightedRoundRobinBalancer::new())),
+        BALANCE_CONSISTENT_HASH => Ok(Box::new(ConsistentHashBalancer::new(HashKey::parse(
+            lb_node.hash_key.as_str(),
+        )?))),
+        BALANCE_RANDOM => Ok(Box::new(RandomBalancer::new())),
+        BALANCE_P2C_LEAST_CONN => Ok(Box::new(P2cLeastConnBalancer::new())),
+        BALANCE_LEAST_LATENCY => Ok(Box::new(LeastLatencyBalancer::new())),
+        BALANCE_LEAST_TRAFFIC => Ok(Box::new(LeastTrafficBalancer::new())),
+        _ => Err(format!("Invalid balance strategy [{}]", lb_node.balance)),
+    }
+}
+
+#[test]
+fn test_least_conn_balancer() {
+    let targets = vec![
+        TargetDump::new("127.0.0.1:1081".to_string(), 100, 3, 60, true, true, 1),
+        TargetDump::new("127.0.0.1:1082".to_string(), 100, 1, 60, true, true, 1),
+        TargetDump::new("127.0.0.1:1083".to_string(), 100, 2, 60, true, true, 1),
+    ];
+    let balancer = LeastConnBalancer::new();
+    let client_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+    let selected = balancer.select(targets, &client_addr);
+    let counts: Vec<u32> = selected.iter().map(|t| t.target_conn_count).collect();
+    assert_eq!(counts, vec![1, 2, 3]);
+}
+
+#[test]
+fn test_weighted_round_robin_balancer() {
+    let targets = vec![
+        TargetDump::new("127.0.0.1:1081".to_string(), 100, 0, 60, true, true, 5),
+        TargetDump::new("127.0.0.1:1082".to_string(), 100, 0, 60, true, true, 1),
+        TargetDump::new("127.0.0.1:1083".to_string(), 100, 0, 60, true, true, 1),
+        TargetDump::new("127.0.0.1:1084".to_string(), 100, 0, 60, true, true, 0),
+    ];
+    let balancer = WeightedRoundRobinBalancer::new();
+    let client_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+    let mut picked = vec![];
+    for _ in 0..7 {
+        let selected = balancer.select(targets.clone(), &client_addr);
+        assert_eq!(selected.len(), 3);
+        picked.push(selected[0].target.target_endpoint.clone());
+    }
+    // nginx smooth weighted round-robin sequence for weights {5, 1, 1}
+    let expected = vec![
+        "127.0.0.1:1081",
+        "127.0.0.1:1081",
+        "127.0.0.1:1082",
+        "127.0.0.1:1081",
+        "127.0.0.1:1083",
+        "127.0.0.1:1081",
+        "127.0.0.1:1081",
+    ];
+    assert_eq!(picked, expected);
+}
+
+#[test]
+fn test_consistent_hash_balancer() {
+    let targets: Vec<TargetDump> = (0..5)
+        .map(|i| TargetDump::new(format!("127.0.0.1:{}", 1081 + i), 100, 0, 60, true, true, 1))
+        .collect();
+    let balancer = ConsistentHashBalancer::new(HashKey::Ip);
+    let target_ids: Vec<String> = targets
+        .iter()
+        .map(|t| calc_target_id_by_endpoint(t.target.target_endpoint.clone()))
+        .collect();
+    balancer.update_targets(target_ids.clone());
+
+    let clients: Vec<SocketAddr> = (0..1000)
+        .map(|i| {
+            format!("10.0.{}.{}:50000", i / 250, i % 250)
+                .parse()
+                .unwrap()
+        })
+        .collect();
+    let before: Vec<String> = clients
+        .iter()
+        .map(|c| {
+            balancer.select(targets.clone(), c)[0]
+                .target
+                .target_endpoint
+                .clone()
+        })
+        .collect();
+
+    // the same ip with another port lands on the same target
+    let same_ip: SocketAddr = "10.0.0.0:50001".parse().unwrap();
+    assert_eq!(
+        balancer.select(targets.clone(), &same_ip)[0]
+            .target
+            .target_endpoint,
+        before[0]
+    );
+
+    // an ineligible target only remaps its own clients, the others stay put
+    let removed = "127.0.0.1:1083".to_string();
+    let remain: Vec<TargetDump> = targets
+        .iter()
+        .filter(|t| t.target.target_endpoint != removed)
+        .cloned()
+        .collect();
+    let mut moved = 0;
+    for (c, b) in clients.iter().zip(before.iter()) {
+        let selected = balancer.select(remain.clone(), c);
+        assert_eq!(selected.len(), 4);
+        if *b != removed {
+            assert_eq!(selected[0].target.target_endpoint, *b);
+        } else {
+            moved += 1;
+        }
+    }
+    assert!(moved > 100 && moved < 300);
+
+    // once it is eligible again its clients come back
+    for (c, b) in clients.iter().zip(before.iter()) {
+        assert_eq!(
+            balancer.select(targets.clone(), c)[0]
+                .target
+                .target_endpoint,
+            *b
+        );
+    }
+
+    // the same holds when the target is removed from the configuration
+    balancer.update_targets(
+        target_ids
+            .into_iter()
+            .filter(|id| *id != calc_target_id_by_endpoint(removed.clone()))
+            .collect(),
+    );
+    for (c, b) in clients.iter().zip(before.iter()) {
+        let selected = balancer.select(remain.clone(), c);
+        if *b != removed {
+            assert_eq!(selected[0].target.target_endpoint, *b);
+        }
+    }
+
+    // a target missing from the ring is still tried last
+    let selected = balancer.select(targets.clone(), &clients[0]);
+    assert_eq!(selected.len(), 5);
+    assert_eq!(selected[4].target.target_endpoint, removed);
+}
+
+#[test]
+fn test_p2c_least_conn_balancer() {
+    let targets = vec![
+        TargetDump::new("127.0.0.1:1081".to_string(), 100, 0, 60, true, true, 1),
+        TargetDump::new("127.0.0.1:1082".to_string(), 100, 50, 60, true, true, 1),
+        TargetDump::new("127.0.0.1:1083".to_string(), 100, 90, 60, true, true, 1),
+    ];
+    let balancer = P2cLeastConnBalancer::new();
+    let client_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+    for _ in 0..100 {
+        let selected = balancer.select(targets.clone(), &client_addr);
+        assert_eq!(selected.len(), 3);
+        // the most loaded target can never win a pair
+        assert_ne!(selected[0].target.target_endpoint, "127.0.0.1:1083");
+        assert!(selected[0].target_conn_count <= selected[1].target_conn_count);
+    }
+}
+
+#[test]
+fn test_least_latency_balancer() {
+    let mut targets = vec![
+        TargetDump::new("127.0.0.1:1081".to_string(), 100, 0, 60, true, true, 1),
+        TargetDump::new("127.0.0.1:1082".to_string(), 100, 5, 60, true, true, 1),
+        TargetDump::new("127.0.0.1:1083".to_string(), 100, 1, 60, true, true, 1),
+    ];
+    targets[0].target.target_connect_latency = 3000;
+    targets[1].target.target_connect_latency = 800;
+    targets[2].target.target_connect_latency = 0;
+    let balancer = LeastLatencyBalancer::new();
+    let client_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+    let selected = balancer.select(targets, &client_addr);
+    let endpoints: Vec<&str> = selected
+        .iter()
+        .map(|t| t.target.target_endpoint.as_str())
+        .collect();
+    assert_eq!(
+        endpoints,
+        vec!["127.0.0.1:1083", "127.0.0.1:1082", "127.0.0.1:1081"]
+    );
+}
+
+#[test]
+fn test_least_traffic_balancer() {
+    let mut targets = vec![
+        TargetDump::new("127.0.0.1:1081".to_string(), 100, 1, 60, true, true, 1),
+        TargetDump::new("127.0.0.1:1082".to_string(), 100, 9, 60, true, true, 1),
+    ];
+    // one heavy stream against many idle connections
+    targets[0].target_traffic_speed = 800_000_000;
+    targets[1].target_traffic_speed = 1_000_000;
+    let balancer = LeastTrafficBalancer::new();
+    let client_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+    let selected = balancer.select(targets, &client_addr);
+    assert_eq!(selected[0].target.target_endpoint, "127.0.0.1:1082");
+}
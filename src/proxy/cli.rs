@@ -0,0 +1,182 @@
+use crate::proxy::config::ConfigFormat;
+use std::env;
+
+pub const DEFAULT_CONFIG_FILE_NAME: &str = "lb-config.json";
+pub const DEFAULT_LOG_DIR: &str = "log";
+
+pub const ENV_CONFIG: &str = "TCP_LB_CONFIG";
+pub const ENV_CONFIG_FORMAT: &str = "TCP_LB_CONFIG_FORMAT";
+pub const ENV_LOG_DIR: &str = "TCP_LB_LOG_DIR";
+pub const ENV_LISTEN: &str = "TCP_LB_LISTEN";
+pub const ENV_API_LISTEN: &str = "TCP_LB_API_LISTEN";
+
+pub const CMD_CONVERT: &str = "convert";
+
+pub const USAGE: &str = "Usage: tcp_lb_rs [OPTIONS]
+       tcp_lb_rs convert --to <json|toml|yaml> [OPTIONS]
+
+Commands:
+    convert                  print the effective config in another format and exit
+
+Options:
+    --config <path>          config file [env: TCP_LB_CONFIG] [default: lb-config.json]
+    --config-format <fmt>    json, toml or yaml [env: TCP_LB_CONFIG_FORMAT]
+                             [default: from the file extension, else json]
+    --to <fmt>               output format of convert
+    --log-dir <dir>          log directory [env: TCP_LB_LOG_DIR] [default: log]
+    --listen <addr>          override lb_node.listen [env: TCP_LB_LISTEN]
+    --api-listen <addr>      override lb_api.listen [env: TCP_LB_API_LISTEN]
+    --check-config           validate the config and exit
+    --version                print version and exit
+    --help                   print this help and exit";
+
+// command line options, the command line wins over the environment
+#[derive(Debug, Clone, PartialEq)]
+pub struct CliOptions {
+    pub config: String,
+    pub config_format: Option<ConfigFormat>,
+    // set by the convert command
+    pub convert_to: Option<ConfigFormat>,
+    pub log_dir: String,
+    pub listen: Option<String>,
+    pub api_listen: Option<String>,
+    pub check_config: bool,
+    pub version: bool,
+    pub help: bool,
+}
+
+impl CliOptions {
+    pub fn parse<I, F>(args: I, get_env: F) -> Result<CliOptions, String>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut options = CliOptions {
+            config: get_env(ENV_CONFIG).unwrap_or_else(|| DEFAULT_CONFIG_FILE_NAME.to_string()),
+            config_format: match get_env(ENV_CONFIG_FORMAT) {
+                Some(format) => Some(ConfigFormat::parse(&format)?),
+                None => None,
+            },
+            convert_to: None,
+            log_dir: get_env(ENV_LOG_DIR).unwrap_or_else(|| DEFAULT_LOG_DIR.to_string()),
+            listen: get_env(ENV_LISTEN),
+            api_listen: get_env(ENV_API_LISTEN),
+            check_config: false,
+            version: false,
+            help: false,
+        };
+
+        let mut args = args.into_iter().peekable();
+        let convert = args.peek().map(|arg| arg.as_str()) == Some(CMD_CONVERT);
+        if convert {
+            args.next();
+        }
+        while let Some(arg) = args.next() {
+            // accept both "--name value" and "--name=value"
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            let mut value = || match inline_value.clone().or_else(|| args.next()) {
+                Some(value) if !value.is_empty() => Ok(value),
+                _ => Err(format!("Missing value for option [{}]", name)),
+            };
+            match name.as_str() {
+                "--config" => options.config = value()?,
+                "--config-format" => options.config_format = Some(ConfigFormat::parse(&value()?)?),
+                "--to" if convert => options.convert_to = Some(ConfigFormat::parse(&value()?)?),
+                "--log-dir" => options.log_dir = value()?,
+                "--listen" => options.listen = Some(value()?),
+                "--api-listen" => options.api_listen = Some(value()?),
+                "--check-config" => options.check_config = true,
+                "--version" | "-V" => options.version = true,
+                "--help" | "-h" => options.help = true,
+                _ => return Err(format!("Unknown option [{}]", arg)),
+            }
+        }
+        if convert && options.convert_to.is_none() {
+            return Err(format!(
+                "Missing option [--to] for command [{}]",
+                CMD_CONVERT
+            ));
+        }
+        Ok(options)
+    }
+
+    // parse the process arguments, print the usage and exit on error
+    pub fn from_env() -> CliOptions {
+        match CliOptions::parse(env::args().skip(1), |key| env::var(key).ok()) {
+            Ok(options) => options,
+            Err(e) => {
+                eprintln!("{}\n\n{}", e, USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn parse_test_args(args: &[&str], envs: &[(&str, &str)]) -> Result<CliOptions, String> {
+    CliOptions::parse(args.iter().map(|arg| arg.to_string()), |key| {
+        envs.iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.to_string())
+    })
+}
+
+#[test]
+fn test_cli_options_default() {
+    let options = parse_test_args(&[], &[]).unwrap();
+    assert_eq!(options.config, DEFAULT_CONFIG_FILE_NAME);
+    assert_eq!(options.log_dir, DEFAULT_LOG_DIR);
+    assert_eq!(options.listen, None);
+    assert!(!options.check_config);
+}
+
+#[test]
+fn test_cli_options_override() {
+    let envs = [
+        (ENV_CONFIG, "/etc/lb/env.json"),
+        (ENV_LISTEN, "0.0.0.0:8081"),
+        (ENV_API_LISTEN, "127.0.0.1:9001"),
+    ];
+    let options = parse_test_args(
+        &[
+            "--config",
+            "/etc/lb/a.json",
+            "--log-dir=/var/log/lb",
+            "--listen",
+            "0.0.0.0:8082",
+            "--check-config",
+        ],
+        &envs,
+    )
+    .unwrap();
+    assert_eq!(options.config, "/etc/lb/a.json");
+    assert_eq!(options.log_dir, "/var/log/lb");
+    assert_eq!(options.listen.as_deref(), Some("0.0.0.0:8082"));
+    assert_eq!(options.api_listen.as_deref(), Some("127.0.0.1:9001"));
+    assert!(options.check_config);
+}
+
+#[test]
+fn test_cli_options_convert() {
+    let options = parse_test_args(
+        &["convert", "--to", "yaml", "--config", "lb.toml"],
+        &[(ENV_CONFIG_FORMAT, "toml")],
+    )
+    .unwrap();
+    assert_eq!(options.convert_to, Some(ConfigFormat::Yaml));
+    assert_eq!(options.config_format, Some(ConfigFormat::Toml));
+
+    assert!(parse_test_args(&["convert"], &[]).is_err());
+    assert!(parse_test_args(&["convert", "--to", "xml"], &[]).is_err());
+    assert!(parse_test_args(&["--to", "yaml"], &[]).is_err());
+}
+
+#[test]
+fn test_cli_options_invalid() {
+    assert!(parse_test_args(&["--config"], &[]).is_err());
+    assert!(parse_test_args(&["--listen="], &[]).is_err());
+    assert!(parse_test_args(&["--unknown"], &[]).is_err());
+}
@@ -1,12 +1,24 @@
 // #[macro_use]
+use crate::proxy::balancer::{HashKey, BALANCE_LEAST_CONN, BALANCE_STRATEGIES, HASH_KEY_IP};
+use crate::proxy::forward::{FORWARDING_BUFFERED, FORWARDING_MODES};
+use crate::proxy::g::CLI_OPTIONS;
+use crate::proxy::health::{
+    HealthCheckProbe, HEALTH_CHECK_HTTP, HEALTH_CHECK_HTTPS, HEALTH_CHECK_TCP,
+};
+use crate::proxy::target::calc_target_id_by_endpoint;
+use flexi_logger::LogSpecification;
+use log::LevelFilter;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
 use std::vec::Vec;
 
-const CONFIG_FILE_NAME: &'static str = "lb-config.json";
+const MAX_BUFFER_SIZE: u32 = 4 * 1024 * 1024;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -24,10 +36,223 @@ pub struct LogConfig {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NodeConfig {
     pub listen: String,
+    // an ipv6 listen address like [::]:8080 also accepts ipv4 clients unless set
+    #[serde(default)]
+    pub ipv6_only: bool,
     pub max_conn: u32,
+    // idle timeout in seconds, a tunnel is closed once both directions are
+    // silent that long, the target_timeout of its target may shorten it
     pub timeout: u32,
     pub enable_local_endpoints: bool,
     pub local_endpoints: Vec<String>,
+    #[serde(default = "default_balance")]
+    pub balance: String,
+    #[serde(default = "default_hash_key")]
+    pub hash_key: String,
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u32,
+    // 0 tries every eligible target
+    #[serde(default)]
+    pub max_connect_attempts: u32,
+    #[serde(default)]
+    pub connect_backoff_ms: u32,
+    #[serde(default)]
+    pub retry_budget: RetryBudgetConfig,
+    // ramp up time of recovered targets, 0 disables slow start
+    #[serde(default)]
+    pub slow_start_secs: u32,
+    // bytes read per chunk by each direction of a tunnel
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: u32,
+    // buffered or splice, splice falls back to buffered where unsupported
+    #[serde(default = "default_forwarding")]
+    pub forwarding: String,
+    // a single write blocked this long closes the tunnel
+    #[serde(default = "default_write_timeout_ms")]
+    pub write_timeout_ms: u32,
+    // absolute lifetime of a tunnel, 0 keeps it as long as it is not idle
+    #[serde(default)]
+    pub max_tunnel_lifetime_secs: u32,
+}
+
+impl NodeConfig {
+    // targets are connected from a local endpoint of their own address family
+    pub fn check_target_family(&self, target_endpoint: &str) -> Result<(), String> {
+        let target_addr = match target_endpoint.parse::<SocketAddr>() {
+            Ok(target_addr) => target_addr,
+            Err(_) => return Ok(()),
+        };
+        if self.enable_local_endpoints
+            && !self.local_endpoints.is_empty()
+            && !self
+                .local_endpoints
+                .iter()
+                .filter_map(|e| e.parse::<SocketAddr>().ok())
+                .any(|e| e.is_ipv6() == target_addr.is_ipv6())
+        {
+            return Err(format!(
+                "No node local endpoint of the address family of target [{}]",
+                target_endpoint
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_balance() -> String {
+    BALANCE_LEAST_CONN.to_string()
+}
+
+fn default_hash_key() -> String {
+    HASH_KEY_IP.to_string()
+}
+
+fn default_connect_timeout_ms() -> u32 {
+    5000
+}
+
+fn default_buffer_size() -> u32 {
+    16 * 1024
+}
+
+fn default_write_timeout_ms() -> u32 {
+    30000
+}
+
+fn default_forwarding() -> String {
+    FORWARDING_BUFFERED.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryBudgetConfig {
+    // retries allowed per second as a share of the accepted connections
+    #[serde(default = "default_retry_ratio")]
+    pub retry_ratio: f64,
+    #[serde(default = "default_min_retries_per_sec")]
+    pub min_retries_per_sec: u32,
+}
+
+impl Default for RetryBudgetConfig {
+    fn default() -> RetryBudgetConfig {
+        RetryBudgetConfig {
+            retry_ratio: default_retry_ratio(),
+            min_retries_per_sec: default_min_retries_per_sec(),
+        }
+    }
+}
+
+fn default_retry_ratio() -> f64 {
+    0.2
+}
+
+fn default_min_retries_per_sec() -> u32 {
+    10
+}
+
+// passive health tracking from the real traffic
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutlierDetectionConfig {
+    // 0 disables the consecutive failures rule
+    #[serde(default = "default_outlier_consecutive_failures")]
+    pub consecutive_failures: u32,
+    // 0 disables the failure ratio rule
+    #[serde(default = "default_outlier_failure_ratio")]
+    pub failure_ratio: f64,
+    #[serde(default = "default_outlier_window_secs")]
+    pub window_secs: u32,
+    #[serde(default = "default_outlier_min_requests")]
+    pub min_requests: u32,
+    #[serde(default = "default_outlier_base_ejection_secs")]
+    pub base_ejection_secs: u32,
+    #[serde(default = "default_outlier_max_ejection_secs")]
+    pub max_ejection_secs: u32,
+    #[serde(default = "default_outlier_max_ejection_percent")]
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierDetectionConfig {
+    fn default() -> OutlierDetectionConfig {
+        OutlierDetectionConfig {
+            consecutive_failures: default_outlier_consecutive_failures(),
+            failure_ratio: default_outlier_failure_ratio(),
+            window_secs: default_outlier_window_secs(),
+            min_requests: default_outlier_min_requests(),
+            base_ejection_secs: default_outlier_base_ejection_secs(),
+            max_ejection_secs: default_outlier_max_ejection_secs(),
+            max_ejection_percent: default_outlier_max_ejection_percent(),
+        }
+    }
+}
+
+impl OutlierDetectionConfig {
+    // every invalid field with its error message
+    pub fn field_errors(&self) -> Vec<(String, String)> {
+        let mut errors = Vec::new();
+        if !(0.0..=1.0).contains(&self.failure_ratio) {
+            errors.push((
+                "failure_ratio".to_string(),
+                format!("Invalid outlier failure ratio [{}]", self.failure_ratio),
+            ));
+        }
+        if self.window_secs == 0 {
+            errors.push((
+                "window_secs".to_string(),
+                "Outlier window must be positive".to_string(),
+            ));
+        }
+        if self.base_ejection_secs == 0 {
+            errors.push((
+                "base_ejection_secs".to_string(),
+                "Outlier base ejection time must be positive".to_string(),
+            ));
+        }
+        if self.max_ejection_secs < self.base_ejection_secs {
+            errors.push((
+                "max_ejection_secs".to_string(),
+                "Outlier max ejection time is less than the base one".to_string(),
+            ));
+        }
+        if self.max_ejection_percent > 100 {
+            errors.push((
+                "max_ejection_percent".to_string(),
+                format!(
+                    "Invalid outlier max ejection percent [{}]",
+                    self.max_ejection_percent
+                ),
+            ));
+        }
+        errors
+    }
+}
+
+fn default_outlier_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_outlier_failure_ratio() -> f64 {
+    0.5
+}
+
+fn default_outlier_window_secs() -> u32 {
+    30
+}
+
+fn default_outlier_min_requests() -> u32 {
+    10
+}
+
+fn default_outlier_base_ejection_secs() -> u32 {
+    30
+}
+
+fn default_outlier_max_ejection_secs() -> u32 {
+    300
+}
+
+fn default_outlier_max_ejection_percent() -> u32 {
+    50
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -36,6 +261,232 @@ pub struct TargetConfig {
     pub target_max_conn: u32,
     pub target_timeout: u32,
     pub target_active: bool,
+    #[serde(default = "default_target_weight")]
+    pub target_weight: u32,
+    #[serde(default)]
+    pub target_backup: bool,
+    #[serde(default)]
+    pub target_health_check: Option<HealthCheckConfig>,
+}
+
+fn default_target_weight() -> u32 {
+    1
+}
+
+impl TargetConfig {
+    pub fn check(&self) -> Result<(), String> {
+        first_error(self.field_errors())
+    }
+
+    // every invalid field with its error message
+    pub fn field_errors(&self) -> Vec<(String, String)> {
+        let mut errors = Vec::new();
+        match self.target_endpoint.parse::<SocketAddr>() {
+            Ok(target_addr) if !target_addr.ip().is_unspecified() && target_addr.port() != 0 => {}
+            _ => errors.push((
+                "target_endpoint".to_string(),
+                format!("Invalid target endpoint [{}]", self.target_endpoint),
+            )),
+        }
+        if self.target_max_conn == 0 {
+            errors.push((
+                "target_max_conn".to_string(),
+                format!(
+                    "Target max conn of [{}] must be positive",
+                    self.target_endpoint
+                ),
+            ));
+        }
+        if let Some(health_check) = &self.target_health_check {
+            for (field, e) in health_check.field_errors() {
+                errors.push((
+                    format!("target_health_check.{}", field),
+                    format!("{} for target [{}]", e, self.target_endpoint),
+                ));
+            }
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HealthCheckConfig {
+    // "tcp", "http" or "https"
+    #[serde(default = "default_health_check_type")]
+    pub check_type: String,
+    // check another port of the target, e.g. an admin port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default = "default_health_check_interval_ms")]
+    pub interval_ms: u32,
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u32,
+    #[serde(default = "default_health_check_rise")]
+    pub rise: u32,
+    #[serde(default = "default_health_check_fall")]
+    pub fall: u32,
+    // payload sent after connect, as an escaped string or as hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_hex: Option<String>,
+    // expected response, as an escaped prefix or as a regex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expect: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expect_regex: Option<String>,
+    #[serde(default = "default_health_check_read_timeout_ms")]
+    pub read_timeout_ms: u32,
+    #[serde(default = "default_health_check_http_method")]
+    pub http_method: String,
+    #[serde(default = "default_health_check_http_path")]
+    pub http_path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_host: Option<String>,
+    #[serde(default = "default_health_check_http_expect_status")]
+    pub http_expect_status: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_expect_body: Option<String>,
+    #[serde(default)]
+    pub tls_skip_verify: bool,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> HealthCheckConfig {
+        HealthCheckConfig {
+            check_type: default_health_check_type(),
+            port: None,
+            interval_ms: default_health_check_interval_ms(),
+            timeout_ms: default_health_check_timeout_ms(),
+            rise: default_health_check_rise(),
+            fall: default_health_check_fall(),
+            send: None,
+            send_hex: None,
+            expect: None,
+            expect_regex: None,
+            read_timeout_ms: default_health_check_read_timeout_ms(),
+            http_method: default_health_check_http_method(),
+            http_path: default_health_check_http_path(),
+            http_host: None,
+            http_expect_status: default_health_check_http_expect_status(),
+            http_expect_body: None,
+            tls_skip_verify: false,
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    pub fn check(&self) -> Result<(), String> {
+        first_error(self.field_errors())
+    }
+
+    // every invalid field with its error message
+    pub fn field_errors(&self) -> Vec<(String, String)> {
+        let mut errors = Vec::new();
+        if self.interval_ms == 0 {
+            errors.push((
+                "interval_ms".to_string(),
+                "Health check interval must be positive".to_string(),
+            ));
+        }
+        if self.timeout_ms == 0 {
+            errors.push((
+                "timeout_ms".to_string(),
+                "Health check timeout must be positive".to_string(),
+            ));
+        }
+        if self.rise == 0 {
+            errors.push((
+                "rise".to_string(),
+                "Health check rise must be positive".to_string(),
+            ));
+        }
+        if self.fall == 0 {
+            errors.push((
+                "fall".to_string(),
+                "Health check fall must be positive".to_string(),
+            ));
+        }
+        if self.read_timeout_ms == 0 {
+            errors.push((
+                "read_timeout_ms".to_string(),
+                "Health check read timeout must be positive".to_string(),
+            ));
+        }
+        match self.check_type.as_str() {
+            HEALTH_CHECK_TCP => {
+                if let Err(e) = HealthCheckProbe::from_config(self) {
+                    errors.push(("send".to_string(), e));
+                }
+            }
+            HEALTH_CHECK_HTTP | HEALTH_CHECK_HTTPS => {
+                if hyper::Method::from_bytes(self.http_method.as_bytes()).is_err() {
+                    errors.push((
+                        "http_method".to_string(),
+                        format!("Invalid health check method [{}]", self.http_method),
+                    ));
+                }
+                if !self.http_path.starts_with('/') {
+                    errors.push((
+                        "http_path".to_string(),
+                        format!("Invalid health check path [{}]", self.http_path),
+                    ));
+                }
+                if self.http_expect_status.is_empty()
+                    || self
+                        .http_expect_status
+                        .iter()
+                        .any(|status| hyper::StatusCode::from_u16(*status).is_err())
+                {
+                    errors.push((
+                        "http_expect_status".to_string(),
+                        "Invalid health check expected status codes".to_string(),
+                    ));
+                }
+            }
+            _ => errors.push((
+                "check_type".to_string(),
+                format!("Invalid health check type [{}]", self.check_type),
+            )),
+        }
+        errors
+    }
+}
+
+fn default_health_check_type() -> String {
+    HEALTH_CHECK_TCP.to_string()
+}
+
+fn default_health_check_interval_ms() -> u32 {
+    5000
+}
+
+fn default_health_check_timeout_ms() -> u32 {
+    2000
+}
+
+fn default_health_check_rise() -> u32 {
+    2
+}
+
+fn default_health_check_fall() -> u32 {
+    3
+}
+
+fn default_health_check_read_timeout_ms() -> u32 {
+    2000
+}
+
+fn default_health_check_http_method() -> String {
+    "GET".to_string()
+}
+
+fn default_health_check_http_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_check_http_expect_status() -> Vec<u16> {
+    vec![200]
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -43,48 +494,363 @@ pub struct ApiConfig {
     pub listen: String,
 }
 
-impl Config {
-    pub fn check(&self) -> Result<(), Box<dyn Error>> {
-        let _: SocketAddr = self
-            .lb_node
-            .listen
-            .parse()
-            .expect(&*format!("Invalid node endpoint [{}]", self.lb_node.listen));
-        for t in self.lb_node.local_endpoints.iter() {
-            let _: SocketAddr = t
-                .parse()
-                .expect(&*format!("Invalid node local endpoint [{}]", t));
-        }
-        for t in self.lb_targets.iter() {
-            let _: SocketAddr = t
-                .target_endpoint
-                .parse()
-                .expect(&*format!("Invalid target endpoint [{}]", t.target_endpoint));
-        }
-        let _: SocketAddr = self
-            .lb_api
-            .listen
-            .parse()
-            .expect(&*format!("Invalid api endpoint [{}]", self.lb_api.listen));
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    // e.g. "lb_targets[1].target_endpoint"
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid config:")?;
+        for e in self.0.iter() {
+            write!(f, "\n  {}", e)?;
+        }
         Ok(())
     }
 }
 
-pub fn read_config() -> Config {
-    let mut config_file = File::open(CONFIG_FILE_NAME)
-        .expect(&*format!("Config file [{}] not found", CONFIG_FILE_NAME));
-    let mut json_str = String::new();
+// a plain level like "info", or a flexi_logger spec like "info,tcp_lb_rs=debug"
+fn is_valid_log_spec(log_spec: &str) -> bool {
+    LogSpecification::parse(log_spec).is_ok()
+        && log_spec
+            .split(',')
+            .all(|part| part.contains('=') || LevelFilter::from_str(part.trim()).is_ok())
+}
+
+fn first_error(field_errors: Vec<(String, String)>) -> Result<(), String> {
+    match field_errors.into_iter().next() {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
+
+impl Config {
+    // collect every problem instead of stopping at the first one
+    pub fn check(&self) -> Result<(), ConfigErrors> {
+        let mut errors = Vec::new();
+        let mut push_error = |path: String, message: String| {
+            errors.push(ConfigError { path, message });
+        };
+
+        if !is_valid_log_spec(&self.lb_log.log_set_level) {
+            push_error(
+                "lb_log.log_set_level".to_string(),
+                format!("Invalid log level [{}]", self.lb_log.log_set_level),
+            );
+        }
+
+        let lb_node = &self.lb_node;
+        if lb_node.listen.parse::<SocketAddr>().is_err() {
+            push_error(
+                "lb_node.listen".to_string(),
+                format!("Invalid node endpoint [{}]", lb_node.listen),
+            );
+        }
+        if lb_node.max_conn == 0 {
+            push_error(
+                "lb_node.max_conn".to_string(),
+                "Node max conn must be positive".to_string(),
+            );
+        }
+        if lb_node.enable_local_endpoints && lb_node.local_endpoints.is_empty() {
+            push_error(
+                "lb_node.local_endpoints".to_string(),
+                "Node local endpoints are enabled but empty".to_string(),
+            );
+        }
+        for (i, t) in lb_node.local_endpoints.iter().enumerate() {
+            if t.parse::<SocketAddr>().is_err() {
+                push_error(
+                    format!("lb_node.local_endpoints[{}]", i),
+                    format!("Invalid node local endpoint [{}]", t),
+                );
+            }
+        }
+        if !BALANCE_STRATEGIES.contains(&lb_node.balance.as_str()) {
+            push_error(
+                "lb_node.balance".to_string(),
+                format!("Invalid balance strategy [{}]", lb_node.balance),
+            );
+        }
+        if let Err(e) = HashKey::parse(&lb_node.hash_key) {
+            push_error("lb_node.hash_key".to_string(), e);
+        }
+        if lb_node.connect_timeout_ms == 0 {
+            push_error(
+                "lb_node.connect_timeout_ms".to_string(),
+                "Node connect timeout must be positive".to_string(),
+            );
+        }
+        if lb_node.write_timeout_ms == 0 {
+            push_error(
+                "lb_node.write_timeout_ms".to_string(),
+                "Node write timeout must be positive".to_string(),
+            );
+        }
+        if lb_node.buffer_size == 0 || lb_node.buffer_size > MAX_BUFFER_SIZE {
+            push_error(
+                "lb_node.buffer_size".to_string(),
+                format!("Node buffer size must be between 1 and {}", MAX_BUFFER_SIZE),
+            );
+        }
+        if !FORWARDING_MODES.contains(&lb_node.forwarding.as_str()) {
+            push_error(
+                "lb_node.forwarding".to_string(),
+                format!("Invalid node forwarding mode [{}]", lb_node.forwarding),
+            );
+        }
+        if lb_node.retry_budget.retry_ratio < 0.0 {
+            push_error(
+                "lb_node.retry_budget.retry_ratio".to_string(),
+                format!(
+                    "Invalid node retry ratio [{}]",
+                    lb_node.retry_budget.retry_ratio
+                ),
+            );
+        }
+        if let Some(outlier_detection) = &lb_node.outlier_detection {
+            for (field, e) in outlier_detection.field_errors() {
+                push_error(format!("lb_node.outlier_detection.{}", field), e);
+            }
+        }
+
+        // targets are keyed by the hash of their endpoint
+        let mut target_ids: HashMap<String, usize> = HashMap::new();
+        for (i, t) in self.lb_targets.iter().enumerate() {
+            for (field, e) in t.field_errors() {
+                push_error(format!("lb_targets[{}].{}", i, field), e);
+            }
+            if let Err(e) = lb_node.check_target_family(&t.target_endpoint) {
+                push_error(format!("lb_targets[{}].target_endpoint", i), e);
+            }
+            let target_id = calc_target_id_by_endpoint(t.target_endpoint.clone());
+            if let Some(first) = target_ids.insert(target_id, i) {
+                push_error(
+                    format!("lb_targets[{}].target_endpoint", i),
+                    format!(
+                        "Duplicate target endpoint [{}] of lb_targets[{}]",
+                        t.target_endpoint, first
+                    ),
+                );
+            }
+        }
+
+        if self.lb_api.listen.parse::<SocketAddr>().is_err() {
+            push_error(
+                "lb_api.listen".to_string(),
+                format!("Invalid api endpoint [{}]", self.lb_api.listen),
+            );
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+}
+
+pub const CONFIG_FORMAT_JSON: &str = "json";
+pub const CONFIG_FORMAT_TOML: &str = "toml";
+pub const CONFIG_FORMAT_YAML: &str = "yaml";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    pub fn parse(format: &str) -> Result<ConfigFormat, String> {
+        match format {
+            CONFIG_FORMAT_JSON => Ok(ConfigFormat::Json),
+            CONFIG_FORMAT_TOML => Ok(ConfigFormat::Toml),
+            CONFIG_FORMAT_YAML | "yml" => Ok(ConfigFormat::Yaml),
+            _ => Err(format!("Invalid config format [{}]", format)),
+        }
+    }
+
+    // detect the format from the file extension, json if unknown
+    pub fn from_file_name(config_file_name: &str) -> ConfigFormat {
+        Path::new(config_file_name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ConfigFormat::parse(&ext.to_lowercase()).ok())
+            .unwrap_or(ConfigFormat::Json)
+    }
+}
+
+pub fn parse_config(config_str: &str, format: ConfigFormat) -> Result<Config, String> {
+    match format {
+        ConfigFormat::Json => serde_json::from_str(config_str)
+            .map_err(|e| format!("Failure while deserializing json config: {}", e)),
+        ConfigFormat::Toml => toml::from_str(config_str)
+            .map_err(|e| format!("Failure while deserializing toml config: {}", e)),
+        ConfigFormat::Yaml => serde_norway::from_str(config_str)
+            .map_err(|e| format!("Failure while deserializing yaml config: {}", e)),
+    }
+}
+
+pub fn format_config(config: &Config, format: ConfigFormat) -> Result<String, String> {
+    match format {
+        ConfigFormat::Json => serde_json::to_string_pretty(config).map_err(|e| e.to_string()),
+        // toml wants the plain values of a table before its sub tables, going
+        // through a toml value reorders them
+        ConfigFormat::Toml => toml::Value::try_from(config)
+            .and_then(|value| toml::to_string_pretty(&value))
+            .map_err(|e| e.to_string()),
+        ConfigFormat::Yaml => serde_norway::to_string(config).map_err(|e| e.to_string()),
+    }
+}
+
+// load the config file given on the command line and apply the overrides
+pub fn load_config() -> Result<Config, String> {
+    let format = CLI_OPTIONS
+        .config_format
+        .unwrap_or_else(|| ConfigFormat::from_file_name(&CLI_OPTIONS.config));
+    let mut config = load_config_file(&CLI_OPTIONS.config, format)?;
+    if let Some(listen) = &CLI_OPTIONS.listen {
+        config.lb_node.listen = listen.clone();
+    }
+    if let Some(api_listen) = &CLI_OPTIONS.api_listen {
+        config.lb_api.listen = api_listen.clone();
+    }
+    Ok(config)
+}
+
+pub fn load_config_file(config_file_name: &str, format: ConfigFormat) -> Result<Config, String> {
+    let mut config_file = File::open(config_file_name)
+        .map_err(|e| format!("Config file [{}] not found: {}", config_file_name, e))?;
+    let mut config_str = String::new();
     config_file
-        .read_to_string(&mut json_str)
-        .expect("Failure while reading config file to string");
-    let config: Config =
-        serde_json::from_str(&json_str).expect("Failure while deserializing json config");
-    config
+        .read_to_string(&mut config_str)
+        .map_err(|e| format!("Failure while reading config file to string: {}", e))?;
+    parse_config(&config_str, format)
+}
+
+pub fn read_config() -> Config {
+    load_config().unwrap_or_else(|e| panic!("{}", e))
 }
 
 #[test]
 fn test_read_config() {
-    let config = read_config();
+    let config = load_config_file("lb-config_example.json", ConfigFormat::Json).unwrap();
     println!("config: {:?}", config);
-    let _ = config.check();
+    config.check().unwrap();
+}
+
+#[test]
+fn test_config_formats() {
+    assert_eq!(
+        ConfigFormat::from_file_name("/etc/lb/lb.YML"),
+        ConfigFormat::Yaml
+    );
+    assert_eq!(ConfigFormat::from_file_name("lb.toml"), ConfigFormat::Toml);
+    assert_eq!(ConfigFormat::from_file_name("lb.conf"), ConfigFormat::Json);
+
+    let config = load_config_file("lb-config_example.json", ConfigFormat::Json).unwrap();
+    let config_value = serde_json::to_value(&config).unwrap();
+    for (file_name, format) in [
+        ("lb-config_example.yaml", ConfigFormat::Yaml),
+        ("lb-config_example.toml", ConfigFormat::Toml),
+    ] {
+        let example = load_config_file(file_name, format).unwrap();
+        assert_eq!(serde_json::to_value(&example).unwrap(), config_value);
+
+        // converting keeps the config intact
+        let converted = parse_config(&format_config(&config, format).unwrap(), format).unwrap();
+        assert_eq!(serde_json::to_value(&converted).unwrap(), config_value);
+    }
+}
+
+#[test]
+fn test_config_check_collects_errors() {
+    let mut config = load_config_file("lb-config_example.json", ConfigFormat::Json).unwrap();
+    config.lb_log.log_set_level = "verbose".to_string();
+    config.lb_node.max_conn = 0;
+    config.lb_node.enable_local_endpoints = true;
+    config.lb_node.local_endpoints.clear();
+    config.lb_node.forwarding = "sendfile".to_string();
+    config.lb_targets[1].target_endpoint = config.lb_targets[0].target_endpoint.clone();
+    config.lb_targets[1].target_max_conn = 0;
+    config.lb_api.listen = "localhost".to_string();
+
+    let paths: Vec<String> = config
+        .check()
+        .unwrap_err()
+        .0
+        .into_iter()
+        .map(|e| e.path)
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            "lb_log.log_set_level",
+            "lb_node.max_conn",
+            "lb_node.local_endpoints",
+            "lb_node.forwarding",
+            "lb_targets[1].target_max_conn",
+            "lb_targets[1].target_endpoint",
+            "lb_api.listen",
+        ]
+    );
+}
+
+#[test]
+fn test_config_check_local_endpoint_family() {
+    let mut config = load_config_file("lb-config_example.json", ConfigFormat::Json).unwrap();
+    config.lb_node.enable_local_endpoints = true;
+    config.lb_node.local_endpoints = vec!["[::1]:0".to_string()];
+    config.lb_targets[1].target_endpoint = "[::1]:8080".to_string();
+
+    let errors = config.check().unwrap_err().0;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, "lb_targets[0].target_endpoint");
+
+    config
+        .lb_node
+        .local_endpoints
+        .push("127.0.0.1:0".to_string());
+    assert!(config.check().is_ok());
+
+    config.lb_node.local_endpoints = vec!["127.0.0.1:0".to_string()];
+    assert!(config.lb_node.check_target_family("127.0.0.1:8080").is_ok());
+    assert!(config.lb_node.check_target_family("[::1]:8080").is_err());
+    config.lb_node.enable_local_endpoints = false;
+    assert!(config.lb_node.check_target_family("[::1]:8080").is_ok());
+}
+
+#[test]
+fn test_target_config_check() {
+    let mut target_config = TargetConfig {
+        target_endpoint: "127.0.0.1:8080".to_string(),
+        target_max_conn: 10,
+        target_timeout: 60,
+        target_active: true,
+        target_weight: 1,
+        target_backup: false,
+        target_health_check: None,
+    };
+    assert!(target_config.check().is_ok());
+
+    for endpoint in ["example.com:80", "127.0.0.1", "0.0.0.0:80", "127.0.0.1:0"] {
+        target_config.target_endpoint = endpoint.to_string();
+        assert!(target_config.check().is_err(), "{}", endpoint);
+    }
+
+    target_config.target_endpoint = "127.0.0.1:8080".to_string();
+    target_config.target_max_conn = 0;
+    assert!(target_config.check().is_err());
 }
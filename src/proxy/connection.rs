@@ -2,9 +2,180 @@ use uuid::Uuid;
 
 use crate::proxy::g::SERVER_INFO;
 use chrono::Utc;
+use log::info;
+use std::collections::HashMap;
 use std::error::Error;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio;
+use tokio::sync::{mpsc, watch};
+
+pub const CLOSE_REASON_FINISHED: &str = "closed by both sides";
+pub const CLOSE_REASON_IDLE_TIMEOUT: &str = "idle timeout";
+pub const CLOSE_REASON_MAX_LIFETIME: &str = "max lifetime reached";
+
+// byte counters of one direction pair, shared by the copy tasks and the registry
+#[derive(Debug)]
+pub struct TrafficCounter {
+    window_1m: TrafficWindow,
+    window_5m: TrafficWindow,
+    window_30m: TrafficWindow,
+    // a read in either direction keeps the tunnel out of the idle timeout
+    last_read_time: AtomicI64,
+}
+
+// bits per second over the last 1m, 5m and 30m
+#[derive(Debug, Clone, Default)]
+pub struct TrafficSpeed {
+    pub read_speed_1m: u64,
+    pub read_speed_5m: u64,
+    pub read_speed_30m: u64,
+    pub write_speed_1m: u64,
+    pub write_speed_5m: u64,
+    pub write_speed_30m: u64,
+}
+
+// byte counts of one averaging window, the previous window is kept on reset
+// so the speed slides over the reset instead of dropping to 0
+#[derive(Debug)]
+struct TrafficWindow {
+    length: i64,
+    start_time: AtomicI64,
+    read_bytes: AtomicU64,
+    write_bytes: AtomicU64,
+    // 0 until the first reset
+    prev_span: AtomicI64,
+    prev_read_bytes: AtomicU64,
+    prev_write_bytes: AtomicU64,
+}
+
+impl TrafficWindow {
+    fn new(length_secs: i64, now: i64) -> TrafficWindow {
+        TrafficWindow {
+            length: length_secs * 1_000_000_000,
+            start_time: AtomicI64::new(now),
+            read_bytes: AtomicU64::new(0),
+            write_bytes: AtomicU64::new(0),
+            prev_span: AtomicI64::new(0),
+            prev_read_bytes: AtomicU64::new(0),
+            prev_write_bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self, now: i64) {
+        let start_time = self.start_time.swap(now, Ordering::Relaxed);
+        self.prev_span.store(now - start_time, Ordering::Relaxed);
+        self.prev_read_bytes.store(
+            self.read_bytes.swap(0, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.prev_write_bytes.store(
+            self.write_bytes.swap(0, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn speed(&self, read: bool, write: bool, now: i64) -> u64 {
+        let mut bytes = 0;
+        let mut prev_bytes = 0;
+        if read {
+            bytes += self.read_bytes.load(Ordering::Relaxed);
+            prev_bytes += self.prev_read_bytes.load(Ordering::Relaxed);
+        }
+        if write {
+            bytes += self.write_bytes.load(Ordering::Relaxed);
+            prev_bytes += self.prev_write_bytes.load(Ordering::Relaxed);
+        }
+        sliding_speed_bits_per_sec(
+            bytes,
+            prev_bytes,
+            self.prev_span.load(Ordering::Relaxed),
+            now - self.start_time.load(Ordering::Relaxed),
+            self.length,
+        )
+    }
+}
+
+// bits per second over the last window length: the current window plus the
+// share of the previous one that still falls inside, in f64 so that large
+// byte counts can not overflow
+fn sliding_speed_bits_per_sec(
+    bytes: u64,
+    prev_bytes: u64,
+    prev_span: i64,
+    elapsed: i64,
+    length: i64,
+) -> u64 {
+    let elapsed = elapsed.max(1) as f64;
+    let mut span = elapsed;
+    let mut bits = bytes as f64 * 8.0;
+    if prev_span > 0 {
+        let prev_part = (length as f64 - elapsed).clamp(0.0, prev_span as f64);
+        bits += prev_bytes as f64 * 8.0 * prev_part / prev_span as f64;
+        span += prev_part;
+    }
+    (bits * 1_000_000_000.0 / span) as u64
+}
+
+impl TrafficCounter {
+    pub fn new(now: i64) -> TrafficCounter {
+        TrafficCounter {
+            window_1m: TrafficWindow::new(60, now),
+            window_5m: TrafficWindow::new(5 * 60, now),
+            window_30m: TrafficWindow::new(30 * 60, now),
+            last_read_time: AtomicI64::new(now),
+        }
+    }
+
+    pub fn add_read_n(&self, read_n: u64) {
+        for window in [&self.window_1m, &self.window_5m, &self.window_30m] {
+            window.read_bytes.fetch_add(read_n, Ordering::Relaxed);
+        }
+        self.last_read_time.store(
+            Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn last_read_time(&self) -> i64 {
+        self.last_read_time.load(Ordering::Relaxed)
+    }
+
+    pub fn add_write_n(&self, write_n: u64) {
+        for window in [&self.window_1m, &self.window_5m, &self.window_30m] {
+            window.write_bytes.fetch_add(write_n, Ordering::Relaxed);
+        }
+    }
+
+    pub fn speed(&self, now: i64) -> TrafficSpeed {
+        TrafficSpeed {
+            read_speed_1m: self.window_1m.speed(true, false, now),
+            read_speed_5m: self.window_5m.speed(true, false, now),
+            read_speed_30m: self.window_30m.speed(true, false, now),
+            write_speed_1m: self.window_1m.speed(false, true, now),
+            write_speed_5m: self.window_5m.speed(false, true, now),
+            write_speed_30m: self.window_30m.speed(false, true, now),
+        }
+    }
+
+    // read plus write speed over the last minute
+    pub fn traffic_speed_1m(&self, now: i64) -> u64 {
+        self.window_1m.speed(true, true, now)
+    }
+
+    pub fn reset_read_write_bytes_1m(&self, now: i64) {
+        self.window_1m.reset(now);
+    }
+
+    pub fn reset_read_write_bytes_5m(&self, now: i64) {
+        self.window_5m.reset(now);
+    }
+
+    pub fn reset_read_write_bytes_30m(&self, now: i64) {
+        self.window_30m.reset(now);
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Connection {
@@ -12,65 +183,20 @@ pub struct Connection {
     pub local_endpoint: String,
     pub remote_endpoint: String,
     pub create_time: i64,
-    pub start_time_1m: i64,
-    pub start_time_5m: i64,
-    pub start_time_30m: i64,
-    pub read_bytes_1m: u64,
-    pub read_bytes_5m: u64,
-    pub read_bytes_30m: u64,
-    pub write_bytes_1m: u64,
-    pub write_bytes_5m: u64,
-    pub write_bytes_30m: u64,
+    pub traffic: Arc<TrafficCounter>,
 }
 
 impl Connection {
     pub fn new(local_endpoint: String, remote_endpoint: String) -> Connection {
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
         Connection {
             connect_id: new_connection_id(),
             local_endpoint,
             remote_endpoint,
-            create_time: Utc::now().timestamp_nanos().clone(),
-            start_time_1m: Utc::now().timestamp_nanos().clone(),
-            start_time_5m: Utc::now().timestamp_nanos().clone(),
-            start_time_30m: Utc::now().timestamp_nanos().clone(),
-            read_bytes_1m: 0,
-            read_bytes_5m: 0,
-            read_bytes_30m: 0,
-            write_bytes_1m: 0,
-            write_bytes_5m: 0,
-            write_bytes_30m: 0,
+            create_time: now,
+            traffic: Arc::new(TrafficCounter::new(now)),
         }
     }
-
-    pub fn add_read_n(&mut self, read_n: u64) {
-        self.read_bytes_1m += read_n.clone();
-        self.read_bytes_5m += read_n.clone();
-        self.read_bytes_30m += read_n.clone();
-    }
-
-    pub fn add_write_n(&mut self, write_n: u64) {
-        self.write_bytes_1m += write_n.clone();
-        self.write_bytes_5m += write_n.clone();
-        self.write_bytes_30m += write_n.clone();
-    }
-
-    pub fn reset_read_write_bytes_1m(&mut self) {
-        self.start_time_1m = Utc::now().timestamp_nanos().clone();
-        self.read_bytes_1m = 0;
-        self.write_bytes_1m = 0;
-    }
-
-    pub fn reset_read_write_bytes_5m(&mut self) {
-        self.start_time_5m = Utc::now().timestamp_nanos().clone();
-        self.read_bytes_5m = 0;
-        self.write_bytes_5m = 0;
-    }
-
-    pub fn reset_read_write_bytes_30m(&mut self) {
-        self.start_time_30m = Utc::now().timestamp_nanos().clone();
-        self.read_bytes_30m = 0;
-        self.write_bytes_30m = 0;
-    }
 }
 
 #[derive(Debug, Clone)]
@@ -84,20 +210,14 @@ impl NodeConnection {
             connection: Connection::new(local_endpoint, remote_endpoint),
         }
     }
-
-    pub fn add_read_n(&mut self, read_n: u64) {
-        self.connection.add_read_n(read_n);
-    }
-
-    pub fn add_write_n(&mut self, write_n: u64) {
-        self.connection.add_write_n(write_n);
-    }
 }
 
 #[derive(Debug, Clone)]
 pub struct TargetConnection {
     pub connection: Connection,
     pub target_id: String,
+    // carries the reason once the tunnel is asked to close
+    pub close_tx: Arc<watch::Sender<Option<String>>>,
 }
 
 impl TargetConnection {
@@ -106,29 +226,178 @@ impl TargetConnection {
         remote_endpoint: String,
         target_id: String,
     ) -> TargetConnection {
+        let (close_tx, _) = watch::channel(None);
         TargetConnection {
             connection: Connection::new(local_endpoint, remote_endpoint),
             target_id,
+            close_tx: Arc::new(close_tx),
         }
     }
 
-    pub fn add_read_n(&mut self, read_n: u64) {
-        self.connection.add_read_n(read_n);
+    pub fn close(&self, reason: &str) {
+        self.close_tx.send_replace(Some(reason.to_string()));
     }
+}
 
-    pub fn add_write_n(&mut self, write_n: u64) {
-        self.connection.add_write_n(write_n);
-    }
+#[derive(Debug, Clone, Default)]
+pub struct TargetTunnelStat {
+    pub conn_count: u32,
+    // bits per second over the last minute
+    pub traffic_speed_1m: u64,
 }
 
-pub async fn get_target_conn_count_by_target_id(target_id: String) -> u32 {
-    let mut target_conn: u32 = 0;
+// aggregate the tunnels of every target in a single pass over the tunnel map
+pub async fn get_targets_tunnel_stat() -> HashMap<String, TargetTunnelStat> {
+    let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let mut targets_stat: HashMap<String, TargetTunnelStat> = HashMap::new();
     for (_, v) in SERVER_INFO.deref().tunnel_info.lock().await.iter() {
-        if v.1.target_id == target_id {
-            target_conn += 1;
+        let stat = targets_stat.entry(v.1.target_id.clone()).or_default();
+        stat.conn_count += 1;
+        stat.traffic_speed_1m += v.1.connection.traffic.traffic_speed_1m(now);
+    }
+    targets_stat
+}
+
+pub async fn count_tunnels_by_target_id(target_id: &str) -> u32 {
+    SERVER_INFO
+        .deref()
+        .tunnel_info
+        .lock()
+        .await
+        .values()
+        .filter(|v| v.1.target_id == target_id)
+        .count() as u32
+}
+
+// signal the matching tunnels to close, return their tunnel ids
+pub async fn close_tunnels<F>(filter: F, reason: &str) -> Vec<String>
+where
+    F: Fn(&NodeConnection, &TargetConnection) -> bool,
+{
+    let mut closed = Vec::new();
+    for (tunnel_id, v) in SERVER_INFO.deref().tunnel_info.lock().await.iter() {
+        if filter(&v.0, &v.1) {
+            v.1.close(reason);
+            closed.push(tunnel_id.clone());
+        }
+    }
+    closed
+}
+
+pub async fn close_tunnel(tunnel_id: &str, reason: &str) -> bool {
+    match SERVER_INFO.deref().tunnel_info.lock().await.get(tunnel_id) {
+        Some(v) => {
+            v.1.close(reason);
+            true
+        }
+        None => false,
+    }
+}
+
+// drop the tunnel from the registry and count why it closed, false if it was
+// already gone
+pub async fn remove_tunnel(tunnel_id: &str, reason: &str) -> bool {
+    let removed = SERVER_INFO
+        .deref()
+        .tunnel_info
+        .lock()
+        .await
+        .remove(tunnel_id);
+    if removed.is_some() {
+        record_tunnel_close(tunnel_id, reason);
+    }
+    removed.is_some()
+}
+
+// a failed direction takes the whole tunnel down, the other direction is
+// signalled with the reason
+pub async fn abort_tunnel(tunnel_id: &str, reason: &str) {
+    let removed = SERVER_INFO
+        .deref()
+        .tunnel_info
+        .lock()
+        .await
+        .remove(tunnel_id);
+    if let Some(v) = removed {
+        v.1.close(reason);
+        record_tunnel_close(tunnel_id, reason);
+    }
+}
+
+fn record_tunnel_close(tunnel_id: &str, reason: &str) {
+    *SERVER_INFO
+        .deref()
+        .tunnel_close_stats
+        .lock()
+        .unwrap()
+        .entry(reason.to_string())
+        .or_insert(0) += 1;
+    info!("|{}| tunnel closed, {}", tunnel_id, reason);
+}
+
+// the smaller of the node and target idle timeouts, 0 leaves it to the other side
+pub fn tunnel_idle_timeout_secs(node_timeout: u32, target_timeout: u32) -> u32 {
+    match (node_timeout, target_timeout) {
+        (0, t) => t,
+        (n, 0) => n,
+        (n, t) => n.min(t),
+    }
+}
+
+// close the tunnel once both directions are silent for idle_timeout_secs or it
+// is older than max_lifetime_secs, 0 disables either limit. Returns when the
+// copy tasks drop their alive_tx.
+pub async fn watch_tunnel(
+    tunnel_id: String,
+    traffic: [Arc<TrafficCounter>; 2],
+    create_time: i64,
+    idle_timeout_secs: u32,
+    max_lifetime_secs: u32,
+    mut alive_rx: mpsc::Receiver<()>,
+) {
+    loop {
+        let mut deadline = i64::MAX;
+        let mut reason = CLOSE_REASON_IDLE_TIMEOUT;
+        if idle_timeout_secs > 0 {
+            let last_read_time = traffic[0].last_read_time().max(traffic[1].last_read_time());
+            deadline = last_read_time + idle_timeout_secs as i64 * 1_000_000_000;
+        }
+        if max_lifetime_secs > 0 {
+            let lifetime_deadline = create_time + max_lifetime_secs as i64 * 1_000_000_000;
+            if lifetime_deadline <= deadline {
+                deadline = lifetime_deadline;
+                reason = CLOSE_REASON_MAX_LIFETIME;
+            }
+        }
+        if deadline == i64::MAX {
+            let _ = alive_rx.recv().await;
+            return;
+        }
+
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        if now >= deadline {
+            abort_tunnel(&tunnel_id, reason).await;
+            return;
+        }
+        let wait = std::time::Duration::from_nanos((deadline - now) as u64);
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = alive_rx.recv() => return,
+        }
+    }
+}
+
+// resolve with the close reason once the tunnel is asked to close
+pub async fn wait_tunnel_close(mut close_rx: watch::Receiver<Option<String>>) -> String {
+    loop {
+        if let Some(reason) = close_rx.borrow_and_update().clone() {
+            return reason;
+        }
+        if close_rx.changed().await.is_err() {
+            // the tunnel is already gone, nothing will ask it to close
+            return std::future::pending().await;
         }
     }
-    target_conn
 }
 
 pub fn new_connection_id() -> String {
@@ -146,32 +415,16 @@ pub async fn start_maintain_loop() -> Result<(), Box<dyn Error>> {
     loop {
         tokio::time::sleep(std::time::Duration::from_secs(60)).await;
 
-        if maintain_index.clone() % 1 == 0 {
-            for (_, v) in SERVER_INFO.deref().tunnel_info.lock().await.iter_mut() {
-                let mut node_connection_dump = v.0.clone();
-                node_connection_dump.connection.reset_read_write_bytes_1m();
-                let mut target_connection_dump = v.1.clone();
-                target_connection_dump
-                    .connection
-                    .reset_read_write_bytes_1m();
-            }
-        } else if maintain_index.clone() % 5 == 0 {
-            for (_, v) in SERVER_INFO.deref().tunnel_info.lock().await.iter_mut() {
-                let mut node_connection_dump = v.0.clone();
-                node_connection_dump.connection.reset_read_write_bytes_5m();
-                let mut target_connection_dump = v.1.clone();
-                target_connection_dump
-                    .connection
-                    .reset_read_write_bytes_5m();
-            }
-        } else if maintain_index.clone() % 30 == 0 {
-            for (_, v) in SERVER_INFO.deref().tunnel_info.lock().await.iter_mut() {
-                let mut node_connection_dump = v.0.clone();
-                node_connection_dump.connection.reset_read_write_bytes_30m();
-                let mut target_connection_dump = v.1.clone();
-                target_connection_dump
-                    .connection
-                    .reset_read_write_bytes_30m();
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        for (_, v) in SERVER_INFO.deref().tunnel_info.lock().await.iter() {
+            for traffic in [&v.0.connection.traffic, &v.1.connection.traffic] {
+                traffic.reset_read_write_bytes_1m(now);
+                if maintain_index.is_multiple_of(5) {
+                    traffic.reset_read_write_bytes_5m(now);
+                }
+                if maintain_index.is_multiple_of(30) {
+                    traffic.reset_read_write_bytes_30m(now);
+                }
             }
         }
 
@@ -194,3 +447,72 @@ fn test_new_tunnel_id() {
         println!("tunnel_id: {:?}", tunnel_id);
     }
 }
+
+#[tokio::test]
+async fn test_wait_tunnel_close() {
+    let target_connection = TargetConnection::new(
+        "127.0.0.1:10000".to_string(),
+        "127.0.0.1:8080".to_string(),
+        "target_id".to_string(),
+    );
+    let close_rx = target_connection.close_tx.subscribe();
+    target_connection.close("killed");
+    assert_eq!(wait_tunnel_close(close_rx).await, "killed");
+
+    // a tunnel removed without a close request keeps running
+    let target_connection = TargetConnection::new(
+        "127.0.0.1:10001".to_string(),
+        "127.0.0.1:8080".to_string(),
+        "target_id".to_string(),
+    );
+    let close_rx = target_connection.close_tx.subscribe();
+    drop(target_connection);
+    let r = tokio::time::timeout(
+        std::time::Duration::from_millis(50),
+        wait_tunnel_close(close_rx),
+    )
+    .await;
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_traffic_counter() {
+    let traffic = TrafficCounter::new(0);
+    traffic.add_read_n(1000);
+    traffic.add_write_n(500);
+    let speed = traffic.speed(1_000_000_000);
+    assert_eq!(speed.read_speed_1m, 8000);
+    assert_eq!(speed.write_speed_30m, 4000);
+    assert_eq!(traffic.traffic_speed_1m(2_000_000_000), 6000);
+
+    // the previous window still counts right after a reset
+    traffic.reset_read_write_bytes_1m(2_000_000_000);
+    let speed = traffic.speed(3_000_000_000);
+    assert_eq!(speed.read_speed_1m, 8000 / 3);
+    assert_eq!(speed.read_speed_5m, 8000 / 3);
+}
+
+#[test]
+fn test_traffic_counter_sliding_window() {
+    // 1 GB/s for a full minute does not overflow
+    let traffic = TrafficCounter::new(0);
+    traffic.add_read_n(60_000_000_000);
+    assert_eq!(traffic.speed(60_000_000_000).read_speed_1m, 8_000_000_000);
+
+    // a busy tunnel keeps its speed across the reset of the window
+    traffic.reset_read_write_bytes_1m(60_000_000_000);
+    traffic.add_read_n(1_000_000_000);
+    assert_eq!(traffic.traffic_speed_1m(61_000_000_000), 8_000_000_000);
+
+    // the previous window fades out over the next one
+    assert_eq!(traffic.traffic_speed_1m(90_000_000_000), 4_133_333_333);
+    assert_eq!(traffic.traffic_speed_1m(120_000_000_000), 133_333_333);
+}
+
+#[test]
+fn test_tunnel_idle_timeout_secs() {
+    assert_eq!(tunnel_idle_timeout_secs(60, 30), 30);
+    assert_eq!(tunnel_idle_timeout_secs(60, 0), 60);
+    assert_eq!(tunnel_idle_timeout_secs(0, 30), 30);
+    assert_eq!(tunnel_idle_timeout_secs(0, 0), 0);
+}
@@ -0,0 +1,280 @@
+use log::warn;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+pub const FORWARDING_BUFFERED: &str = "buffered";
+pub const FORWARDING_SPLICE: &str = "splice";
+pub const FORWARDING_MODES: [&str; 2] = [FORWARDING_BUFFERED, FORWARDING_SPLICE];
+
+// moves one chunk at a time from a read half to a write half, the copy tasks
+// time and account each read and write themselves
+pub struct Forwarder {
+    buf: Vec<u8>,
+    #[cfg(target_os = "linux")]
+    pipe: Option<splice::Pipe>,
+    #[cfg(target_os = "linux")]
+    buffer_size: usize,
+}
+
+impl Forwarder {
+    pub fn new(forwarding: &str, buffer_size: usize) -> Forwarder {
+        #[cfg(target_os = "linux")]
+        {
+            if forwarding == FORWARDING_SPLICE {
+                match splice::Pipe::new(buffer_size) {
+                    Ok(pipe) => {
+                        return Forwarder {
+                            buf: Vec::new(),
+                            pipe: Some(pipe),
+                            buffer_size,
+                        }
+                    }
+                    Err(e) => warn!(
+                        "splice forwarding unavailable, using buffered; err = {:?}",
+                        e
+                    ),
+                }
+            }
+            Forwarder {
+                buf: vec![0; buffer_size],
+                pipe: None,
+                buffer_size,
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            if forwarding == FORWARDING_SPLICE {
+                static WARN_ONCE: std::sync::Once = std::sync::Once::new();
+                WARN_ONCE.call_once(|| {
+                    warn!("splice forwarding is only supported on linux, using buffered")
+                });
+            }
+            Forwarder {
+                buf: vec![0; buffer_size],
+            }
+        }
+    }
+
+    // read the next chunk, 0 means the peer closed its write side
+    pub async fn read(&mut self, reader: &mut OwnedReadHalf) -> io::Result<usize> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(pipe) = self.pipe.as_mut() {
+                match pipe.splice_from(reader).await {
+                    Err(e) if splice::is_unsupported(&e) => self.fall_back(&e),
+                    r => return r,
+                }
+            }
+        }
+        reader.read(&mut self.buf).await
+    }
+
+    // write the whole chunk of the last read
+    pub async fn write(&mut self, writer: &mut OwnedWriteHalf, count: usize) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(pipe) = self.pipe.as_mut() {
+                match pipe.splice_to(writer).await {
+                    Err(e) if splice::is_unsupported(&e) => {
+                        // push what is left in the pipe through user space
+                        let rest = pipe.take_pending()?;
+                        self.fall_back(&e);
+                        return writer.write_all(&rest).await;
+                    }
+                    r => return r,
+                }
+            }
+        }
+        writer.write_all(&self.buf[0..count]).await
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fall_back(&mut self, e: &io::Error) {
+        warn!("splice forwarding failed, using buffered; err = {:?}", e);
+        self.pipe = None;
+        self.buf = vec![0; self.buffer_size];
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod splice {
+    use std::io;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use tokio::io::Interest;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::TcpStream;
+
+    // a non blocking pipe the chunk passes through, it is empty between chunks
+    pub struct Pipe {
+        read_fd: RawFd,
+        write_fd: RawFd,
+        chunk_size: usize,
+        pending: usize,
+    }
+
+    impl Pipe {
+        pub fn new(buffer_size: usize) -> io::Result<Pipe> {
+            let mut fds: [libc::c_int; 2] = [0; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut pipe = Pipe {
+                read_fd: fds[0],
+                write_fd: fds[1],
+                chunk_size: buffer_size,
+                pending: 0,
+            };
+            // the kernel rounds the size up to pages and caps it at pipe-max-size
+            let mut capacity =
+                unsafe { libc::fcntl(pipe.write_fd, libc::F_SETPIPE_SZ, buffer_size as i32) };
+            if capacity < 0 {
+                capacity = unsafe { libc::fcntl(pipe.write_fd, libc::F_GETPIPE_SZ) };
+            }
+            if capacity < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            pipe.chunk_size = buffer_size.min(capacity as usize);
+            Ok(pipe)
+        }
+
+        pub async fn splice_from(&mut self, reader: &mut OwnedReadHalf) -> io::Result<usize> {
+            let stream: &TcpStream = reader.as_ref();
+            loop {
+                stream.readable().await?;
+                match stream.try_io(Interest::READABLE, || {
+                    splice(stream.as_raw_fd(), self.write_fd, self.chunk_size)
+                }) {
+                    Ok(n) => {
+                        self.pending = n;
+                        return Ok(n);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        pub async fn splice_to(&mut self, writer: &mut OwnedWriteHalf) -> io::Result<()> {
+            let stream: &TcpStream = writer.as_ref();
+            while self.pending > 0 {
+                stream.writable().await?;
+                match stream.try_io(Interest::WRITABLE, || {
+                    splice(self.read_fd, stream.as_raw_fd(), self.pending)
+                }) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => self.pending -= n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+
+        // copy the bytes still in the pipe out to user space
+        pub fn take_pending(&mut self) -> io::Result<Vec<u8>> {
+            let mut rest = vec![0u8; self.pending];
+            let mut filled = 0;
+            while filled < rest.len() {
+                let n = unsafe {
+                    libc::read(
+                        self.read_fd,
+                        rest[filled..].as_mut_ptr() as *mut libc::c_void,
+                        rest.len() - filled,
+                    )
+                };
+                if n <= 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                filled += n as usize;
+            }
+            self.pending = 0;
+            Ok(rest)
+        }
+    }
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.read_fd);
+                libc::close(self.write_fd);
+            }
+        }
+    }
+
+    fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+        let n = unsafe {
+            libc::splice(
+                fd_in,
+                std::ptr::null_mut(),
+                fd_out,
+                std::ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    // errors meaning splice can not be used on these descriptors at all
+    pub fn is_unsupported(e: &io::Error) -> bool {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP)
+        )
+    }
+}
+
+#[cfg(test)]
+async fn forward_through(forwarding: &str, payload: &[u8]) -> (bool, Vec<u8>) {
+    use tokio::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    let sink_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let sink_addr = sink_listener.local_addr().unwrap();
+    let upstream = TcpStream::connect(sink_addr).await.unwrap();
+    let (mut sink, _) = sink_listener.accept().await.unwrap();
+
+    let (mut server_read, _server_write) = server.into_split();
+    let (_upstream_read, mut upstream_write) = upstream.into_split();
+    let mut forwarder = Forwarder::new(forwarding, 1024);
+    #[cfg(target_os = "linux")]
+    let is_splice = forwarder.pipe.is_some();
+    #[cfg(not(target_os = "linux"))]
+    let is_splice = false;
+
+    client.write_all(payload).await.unwrap();
+    client.shutdown().await.unwrap();
+    loop {
+        let n = forwarder.read(&mut server_read).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        forwarder.write(&mut upstream_write, n).await.unwrap();
+    }
+    drop(upstream_write);
+
+    let mut received = Vec::new();
+    sink.read_to_end(&mut received).await.unwrap();
+    (is_splice, received)
+}
+
+#[tokio::test]
+async fn test_forwarder() {
+    let payload: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+
+    let (is_splice, received) = forward_through(FORWARDING_BUFFERED, &payload).await;
+    assert!(!is_splice);
+    assert_eq!(received, payload);
+
+    let (is_splice, received) = forward_through(FORWARDING_SPLICE, &payload).await;
+    assert_eq!(is_splice, cfg!(target_os = "linux"));
+    assert_eq!(received, payload);
+}
@@ -1,7 +1,11 @@
+use crate::proxy::cli::CliOptions;
 use crate::proxy::proxy::ProxyServer;
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 
 lazy_static! {
+    pub static ref CLI_OPTIONS: CliOptions = CliOptions::from_env();
     pub static ref SERVER_INFO: ProxyServer = ProxyServer::new();
     pub static ref NODE_LOCAL_SELECTOR: AtomicU64 = AtomicU64::new(0);
+    pub static ref TARGET_BACKUP_IN_USE: AtomicBool = AtomicBool::new(false);
+    pub static ref CONFIG_RELOAD_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
 }
@@ -0,0 +1,536 @@
+use crate::proxy::config::HealthCheckConfig;
+use crate::proxy::g::SERVER_INFO;
+use chrono::Utc;
+use hyper::body::HttpBody;
+use hyper::{Body, Request};
+use log::{debug, info, warn};
+use regex::bytes::Regex;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::Arc;
+use tokio;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Duration;
+use tokio_rustls::TlsConnector;
+
+pub const HEALTH_CHECK_TCP: &str = "tcp";
+pub const HEALTH_CHECK_HTTP: &str = "http";
+pub const HEALTH_CHECK_HTTPS: &str = "https";
+
+lazy_static! {
+    static ref TLS_CONNECTOR: TlsConnector = new_tls_connector(false);
+    static ref TLS_CONNECTOR_SKIP_VERIFY: TlsConnector = new_tls_connector(true);
+}
+
+// granularity of the health check scheduler
+const HEALTH_CHECK_TICK_MS: u64 = 100;
+// never buffer more than this while waiting for the expected response
+const HEALTH_CHECK_MAX_RESPONSE: usize = 4096;
+
+#[derive(Debug)]
+pub enum ExpectPattern {
+    Prefix(Vec<u8>),
+    Regex(Regex),
+}
+
+impl ExpectPattern {
+    // Some(result) once the response is conclusive, None if more bytes are needed
+    fn check(&self, response: &[u8], eof: bool) -> Option<bool> {
+        match self {
+            ExpectPattern::Prefix(prefix) => {
+                if response.len() >= prefix.len() {
+                    Some(response.starts_with(prefix))
+                } else if eof || !prefix.starts_with(response) {
+                    Some(false)
+                } else {
+                    None
+                }
+            }
+            ExpectPattern::Regex(regex) => {
+                if regex.is_match(response) {
+                    Some(true)
+                } else if eof {
+                    Some(false)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HealthCheckProbe {
+    pub send: Option<Vec<u8>>,
+    pub expect: Option<ExpectPattern>,
+}
+
+impl HealthCheckProbe {
+    pub fn from_config(health_check: &HealthCheckConfig) -> Result<HealthCheckProbe, String> {
+        let send = match (&health_check.send, &health_check.send_hex) {
+            (Some(_), Some(_)) => {
+                return Err("Health check send and send_hex are exclusive".to_string())
+            }
+            (Some(send), None) => Some(unescape_payload(send)?),
+            (None, Some(send_hex)) => Some(decode_hex_payload(send_hex)?),
+            (None, None) => None,
+        };
+        let expect = match (&health_check.expect, &health_check.expect_regex) {
+            (Some(_), Some(_)) => {
+                return Err("Health check expect and expect_regex are exclusive".to_string())
+            }
+            (Some(expect), None) => Some(ExpectPattern::Prefix(unescape_payload(expect)?)),
+            (None, Some(expect_regex)) => Some(ExpectPattern::Regex(
+                Regex::new(expect_regex)
+                    .map_err(|e| format!("Invalid health check expect_regex: {}", e))?,
+            )),
+            (None, None) => None,
+        };
+        Ok(HealthCheckProbe { send, expect })
+    }
+}
+
+// accept \r \n \t \0 \\ and \xNN escapes, e.g. "PING\r\n"
+pub fn unescape_payload(payload: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(payload.len());
+    let mut chars = payload.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                // exactly two hex digits, from_str_radix alone would take "4" or "+f"
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("Invalid escape \\x{} in payload", hex));
+                }
+                bytes.push(u8::from_str_radix(&hex, 16).unwrap());
+            }
+            Some(c) => return Err(format!("Invalid escape \\{} in payload", c)),
+            None => return Err("Dangling escape at the end of payload".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+pub fn decode_hex_payload(payload: &str) -> Result<Vec<u8>, String> {
+    let hex: Vec<char> = payload.chars().filter(|c| !c.is_whitespace()).collect();
+    if !hex.len().is_multiple_of(2) || !hex.iter().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hex payload [{}]", payload));
+    }
+    hex.chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("Invalid hex payload [{}]", payload))
+        })
+        .collect()
+}
+
+async fn check_tcp_payload(
+    tcp_stream: &mut tokio::net::TcpStream,
+    health_check: &HealthCheckConfig,
+) -> bool {
+    let probe = match HealthCheckProbe::from_config(health_check) {
+        Ok(probe) => probe,
+        Err(_) => return false,
+    };
+
+    let read_timeout = Duration::from_millis(health_check.read_timeout_ms as u64);
+    if let Some(send) = &probe.send {
+        match tokio::time::timeout(read_timeout, tcp_stream.write_all(send)).await {
+            Ok(Ok(_)) => (),
+            _ => return false,
+        }
+    }
+
+    let expect = match &probe.expect {
+        Some(expect) => expect,
+        None => return true,
+    };
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    let deadline = tokio::time::Instant::now() + read_timeout;
+    loop {
+        let n = match tokio::time::timeout_at(deadline, tcp_stream.read(&mut buf)).await {
+            Ok(Ok(n)) => n,
+            _ => return false,
+        };
+        response.extend_from_slice(&buf[0..n]);
+        if let Some(result) = expect.check(&response, n == 0) {
+            return result;
+        }
+        if response.len() >= HEALTH_CHECK_MAX_RESPONSE {
+            return false;
+        }
+    }
+}
+
+async fn check_http<T>(io: T, health_check: &HealthCheckConfig, host: String) -> bool
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = match hyper::client::conn::handshake(io).await {
+        Ok(handshake) => handshake,
+        Err(_) => return false,
+    };
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let req = match Request::builder()
+        .method(health_check.http_method.as_str())
+        .uri(health_check.http_path.as_str())
+        .header(hyper::header::HOST, host)
+        .header(hyper::header::USER_AGENT, "tcp_lb_rs health check")
+        .body(Body::empty())
+    {
+        Ok(req) => req,
+        Err(_) => return false,
+    };
+    let resp = match sender.send_request(req).await {
+        Ok(resp) => resp,
+        Err(_) => return false,
+    };
+    if !health_check
+        .http_expect_status
+        .contains(&resp.status().as_u16())
+    {
+        return false;
+    }
+
+    let expect_body = match &health_check.http_expect_body {
+        Some(expect_body) => expect_body,
+        None => return true,
+    };
+    let mut body = resp.into_body();
+    let mut response = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => return false,
+        };
+        response.extend_from_slice(&chunk);
+        if contains_bytes(&response, expect_body.as_bytes()) {
+            return true;
+        }
+        if response.len() >= HEALTH_CHECK_MAX_RESPONSE {
+            break;
+        }
+    }
+    false
+}
+
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+async fn check_target_health(target_endpoint: String, health_check: HealthCheckConfig) -> bool {
+    let mut target_addr: SocketAddr = match target_endpoint.parse() {
+        Ok(addr) => addr,
+        Err(_) => return false,
+    };
+    if let Some(port) = health_check.port {
+        target_addr.set_port(port);
+    }
+
+    let timeout = Duration::from_millis(health_check.timeout_ms as u64);
+    let mut tcp_stream =
+        match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(target_addr)).await {
+            Ok(Ok(tcp_stream)) => tcp_stream,
+            _ => return false,
+        };
+
+    let read_timeout = Duration::from_millis(health_check.read_timeout_ms as u64);
+    let host = health_check
+        .http_host
+        .clone()
+        .unwrap_or_else(|| target_addr.to_string());
+    let healthy = match health_check.check_type.as_str() {
+        HEALTH_CHECK_HTTP => {
+            let check = check_http(tcp_stream, &health_check, host);
+            matches!(tokio::time::timeout(read_timeout, check).await, Ok(true))
+        }
+        HEALTH_CHECK_HTTPS => {
+            let check = async {
+                let server_name = match tls_server_name(&host) {
+                    Some(server_name) => server_name,
+                    None => return false,
+                };
+                let connector = if health_check.tls_skip_verify {
+                    TLS_CONNECTOR_SKIP_VERIFY.clone()
+                } else {
+                    TLS_CONNECTOR.clone()
+                };
+                match connector.connect(server_name, tcp_stream).await {
+                    Ok(tls_stream) => check_http(tls_stream, &health_check, host).await,
+                    Err(_) => false,
+                }
+            };
+            matches!(tokio::time::timeout(read_timeout, check).await, Ok(true))
+        }
+        _ => check_tcp_payload(&mut tcp_stream, &health_check).await,
+    };
+    if !healthy {
+        debug!(
+            "{} health check of [{}] failed",
+            health_check.check_type, target_addr
+        );
+    }
+    healthy
+}
+
+// SNI and certificate name come from the Host header without its port
+fn tls_server_name(host: &str) -> Option<rustls::ServerName> {
+    let name = match host.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => host
+            .rsplit_once(':')
+            .map_or(host, |(name, _)| name)
+            .to_string(),
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    rustls::ServerName::try_from(name).ok()
+}
+
+struct SkipServerVerification {}
+
+impl rustls::client::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+fn new_tls_connector(skip_verify: bool) -> TlsConnector {
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let mut tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    if skip_verify {
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(SkipServerVerification {}));
+    }
+    TlsConnector::from(Arc::new(tls_config))
+}
+
+async fn update_target_health(target_id: String, healthy: bool) {
+    let mut targets_info = SERVER_INFO.deref().targets_info.lock().await;
+    let target = match targets_info.get_mut(&target_id) {
+        Some(target) => target,
+        None => return,
+    };
+    if target.apply_health_check_result(healthy) {
+        if target.target_status {
+            info!(
+                "target |{}| [{}] is healthy again after {} successful checks",
+                target_id, target.target_endpoint, target.target_health_success
+            );
+        } else {
+            warn!(
+                "target |{}| [{}] is unhealthy after {} failed checks",
+                target_id, target.target_endpoint, target.target_health_failure
+            );
+        }
+    }
+}
+
+pub async fn start_health_check_loop() -> Result<(), Box<dyn Error>> {
+    loop {
+        tokio::time::sleep(Duration::from_millis(HEALTH_CHECK_TICK_MS)).await;
+
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let mut due_checks = vec![];
+        for (k, v) in SERVER_INFO.deref().targets_info.lock().await.iter_mut() {
+            if let Some(health_check) = v.start_health_check(now) {
+                due_checks.push((k.clone(), v.target_endpoint.clone(), health_check));
+            }
+        }
+
+        for (target_id, target_endpoint, health_check) in due_checks {
+            tokio::spawn(async move {
+                let healthy = check_target_health(target_endpoint, health_check).await;
+                update_target_health(target_id, healthy).await;
+            });
+        }
+    }
+}
+
+#[test]
+fn test_apply_health_check_result() {
+    use crate::proxy::target::Target;
+
+    let mut target = Target::new("127.0.0.1:1081".to_string(), 100, 60, true, true, 1, false);
+    target.target_health_check = Some(HealthCheckConfig {
+        interval_ms: 1000,
+        timeout_ms: 500,
+        rise: 2,
+        fall: 3,
+        ..HealthCheckConfig::default()
+    });
+
+    assert!(!target.apply_health_check_result(false));
+    assert!(!target.apply_health_check_result(false));
+    assert!(target.apply_health_check_result(false));
+    assert!(!target.target_status);
+
+    // a single success is not enough to rise
+    assert!(!target.apply_health_check_result(true));
+    assert!(!target.apply_health_check_result(false));
+    assert!(!target.apply_health_check_result(true));
+    assert!(target.apply_health_check_result(true));
+    assert!(target.target_status);
+}
+
+#[test]
+fn test_start_health_check() {
+    use crate::proxy::target::Target;
+
+    let mut target = Target::new("127.0.0.1:1081".to_string(), 100, 60, true, true, 1, false);
+    assert!(target.start_health_check(0).is_none());
+    target.target_health_check = Some(HealthCheckConfig {
+        interval_ms: 1000,
+        timeout_ms: 500,
+        read_timeout_ms: 5000,
+        ..HealthCheckConfig::default()
+    });
+
+    let second = 1_000_000_000;
+    assert!(target.start_health_check(10 * second).is_some());
+    assert!(target
+        .start_health_check(10 * second + second / 2)
+        .is_none());
+    // a slow check is never overlapped by the next one
+    assert!(target.start_health_check(15 * second).is_none());
+    target.apply_health_check_result(true);
+    assert!(target.start_health_check(15 * second).is_some());
+}
+
+#[tokio::test]
+async fn test_check_target_health() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = listener.local_addr().unwrap().to_string();
+    assert!(check_target_health(endpoint.clone(), HealthCheckConfig::default()).await);
+
+    drop(listener);
+    assert!(!check_target_health(endpoint, HealthCheckConfig::default()).await);
+}
+
+#[test]
+fn test_health_check_payload() {
+    assert_eq!(
+        unescape_payload("PING\\r\\n").unwrap(),
+        b"PING\r\n".to_vec()
+    );
+    assert_eq!(unescape_payload("\\x2bOK").unwrap(), b"+OK".to_vec());
+    assert!(unescape_payload("bad\\q").is_err());
+    assert_eq!(unescape_payload("\\x0a\\xFF").unwrap(), vec![0x0a, 0xff]);
+    for payload in ["\\x4", "\\x+f", "\\x-1", "\\xg0", "\\x"] {
+        assert!(unescape_payload(payload).is_err(), "{}", payload);
+    }
+    assert_eq!(decode_hex_payload("50 49 4e 47").unwrap(), b"PING".to_vec());
+    assert!(decode_hex_payload("5").is_err());
+    assert!(decode_hex_payload("+f+f").is_err());
+
+    let prefix = ExpectPattern::Prefix(b"+PONG".to_vec());
+    assert_eq!(prefix.check(b"+PO", false), None);
+    assert_eq!(prefix.check(b"+PONG\r\n", false), Some(true));
+    assert_eq!(prefix.check(b"-ERR", false), Some(false));
+    let regex = ExpectPattern::Regex(Regex::new("^220 ").unwrap());
+    assert_eq!(regex.check(b"220 smtp ready", false), Some(true));
+    assert_eq!(regex.check(b"554 ", true), Some(false));
+}
+
+#[tokio::test]
+async fn test_check_target_health_send_expect() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut tcp_stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 64];
+            let n = tcp_stream.read(&mut buf).await.unwrap();
+            if &buf[0..n] == b"PING\r\n" {
+                let _ = tcp_stream.write_all(b"+PONG\r\n").await;
+            }
+        }
+    });
+
+    let mut health_check = HealthCheckConfig {
+        send: Some("PING\\r\\n".to_string()),
+        expect: Some("+PONG".to_string()),
+        ..HealthCheckConfig::default()
+    };
+    assert!(check_target_health(endpoint.clone(), health_check.clone()).await);
+
+    health_check.send = Some("QUIT\\r\\n".to_string());
+    health_check.read_timeout_ms = 200;
+    assert!(!check_target_health(endpoint, health_check).await);
+}
+
+#[tokio::test]
+async fn test_check_target_health_http() {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+
+    let service = make_service_fn(|_| async {
+        Ok::<_, hyper::Error>(service_fn(|req: Request<Body>| async move {
+            let resp = match req.uri().path() {
+                "/healthz" => Response::new(Body::from("status: ok")),
+                _ => Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::from("status: down"))
+                    .unwrap(),
+            };
+            Ok::<_, hyper::Error>(resp)
+        }))
+    });
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+    let admin_port = server.local_addr().port();
+    tokio::spawn(server);
+
+    // the traffic port is closed, only the admin port answers
+    let mut health_check = HealthCheckConfig {
+        check_type: HEALTH_CHECK_HTTP.to_string(),
+        port: Some(admin_port),
+        http_path: "/healthz".to_string(),
+        http_expect_body: Some("ok".to_string()),
+        ..HealthCheckConfig::default()
+    };
+    assert!(health_check.check().is_ok());
+    assert!(check_target_health("127.0.0.1:1".to_string(), health_check.clone()).await);
+
+    health_check.http_expect_body = Some("degraded".to_string());
+    assert!(!check_target_health("127.0.0.1:1".to_string(), health_check.clone()).await);
+
+    health_check.http_path = "/".to_string();
+    health_check.http_expect_body = None;
+    assert!(!check_target_health("127.0.0.1:1".to_string(), health_check.clone()).await);
+    health_check.http_expect_status = vec![200, 503];
+    assert!(check_target_health("127.0.0.1:1".to_string(), health_check).await);
+}
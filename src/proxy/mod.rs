@@ -1,6 +1,13 @@
 pub mod api;
+pub mod balancer;
+pub mod cli;
 pub mod config;
 pub mod connection;
+pub mod forward;
 pub mod g;
+pub mod health;
+pub mod outlier;
 pub mod proxy;
+pub mod reload;
+pub mod retry;
 pub mod target;
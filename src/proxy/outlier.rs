@@ -0,0 +1,210 @@
+use crate::proxy::config::OutlierDetectionConfig;
+use crate::proxy::g::SERVER_INFO;
+use crate::proxy::target::Target;
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Deref;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct OutlierState {
+    pub consecutive_failures: u32,
+    pub window_start: i64,
+    pub window_total: u32,
+    pub window_failures: u32,
+    // grows with every ejection and decays by one per quiet window
+    pub ejection_count: u32,
+    // 0 when the target is not ejected
+    pub ejected_until: i64,
+}
+
+impl OutlierState {
+    pub fn is_ejected(&self, now: i64) -> bool {
+        self.ejected_until > now
+    }
+
+    // count one traffic result, return true if the target turned into an outlier
+    pub fn record(&mut self, success: bool, now: i64, config: &OutlierDetectionConfig) -> bool {
+        if now - self.window_start > config.window_secs as i64 * NANOS_PER_SEC {
+            if self.window_start != 0 && !self.is_ejected(now) {
+                self.ejection_count = self.ejection_count.saturating_sub(1);
+            }
+            self.window_start = now;
+            self.window_total = 0;
+            self.window_failures = 0;
+        }
+
+        self.window_total += 1;
+        if success {
+            self.consecutive_failures = 0;
+            return false;
+        }
+        self.window_failures += 1;
+        self.consecutive_failures += 1;
+
+        if config.consecutive_failures > 0
+            && self.consecutive_failures >= config.consecutive_failures
+        {
+            return true;
+        }
+        self.window_total >= config.min_requests
+            && config.failure_ratio > 0.0
+            && self.window_failures as f64 >= config.failure_ratio * self.window_total as f64
+    }
+
+    pub fn eject(&mut self, now: i64, config: &OutlierDetectionConfig) -> u64 {
+        // the ejection time doubles on every repeated ejection
+        let shift = self.ejection_count.min(16);
+        let ejection_secs = (config.base_ejection_secs as u64)
+            .saturating_mul(1 << shift)
+            .min(config.max_ejection_secs as u64);
+        self.ejection_count += 1;
+        self.ejected_until = now + ejection_secs as i64 * NANOS_PER_SEC;
+        self.consecutive_failures = 0;
+        self.window_start = now;
+        self.window_total = 0;
+        self.window_failures = 0;
+        ejection_secs
+    }
+}
+
+fn ejection_allowed(
+    targets_info: &HashMap<String, Target>,
+    now: i64,
+    config: &OutlierDetectionConfig,
+) -> bool {
+    let ejected = targets_info
+        .values()
+        .filter(|t| t.target_outlier.is_ejected(now))
+        .count();
+    (ejected + 1) * 100 <= targets_info.len() * config.max_ejection_percent as usize
+}
+
+pub async fn record_target_result(target_id: &str, success: bool) {
+    let server_config = SERVER_INFO.deref().config();
+    let config = match &server_config.lb_node.outlier_detection {
+        Some(config) => config,
+        None => return,
+    };
+    let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+
+    let mut targets_info = SERVER_INFO.deref().targets_info.lock().await;
+    let is_outlier = match targets_info.get_mut(target_id) {
+        Some(target) => {
+            if target.target_outlier.ejected_until != 0 && !target.target_outlier.is_ejected(now) {
+                target.target_outlier.ejected_until = 0;
+                info!(
+                    "target |{}| [{}] returns from ejection",
+                    target_id, target.target_endpoint
+                );
+            }
+            target.target_outlier.record(success, now, config)
+        }
+        None => return,
+    };
+    if !is_outlier {
+        return;
+    }
+
+    if !ejection_allowed(&targets_info, now, config) {
+        warn!(
+            "target |{}| is an outlier but {}% of targets are already ejected",
+            target_id, config.max_ejection_percent
+        );
+        return;
+    }
+    if let Some(target) = targets_info.get_mut(target_id) {
+        let ejection_secs = target.target_outlier.eject(now, config);
+        // ramp up again once the ejection is over
+        target.target_slow_start_begin = target.target_outlier.ejected_until;
+        warn!(
+            "target |{}| [{}] ejected for {}s, ejection count {}",
+            target_id, target.target_endpoint, ejection_secs, target.target_outlier.ejection_count
+        );
+    }
+}
+
+#[test]
+fn test_outlier_consecutive_failures() {
+    let config = OutlierDetectionConfig::default();
+    let mut state = OutlierState::default();
+    let now = NANOS_PER_SEC;
+    for _ in 1..config.consecutive_failures {
+        assert!(!state.record(false, now, &config));
+    }
+    // a success resets the consecutive count
+    assert!(!state.record(true, now, &config));
+    for _ in 1..config.consecutive_failures {
+        assert!(!state.record(false, now, &config));
+    }
+    assert!(state.record(false, now, &config));
+
+    assert_eq!(state.eject(now, &config), config.base_ejection_secs as u64);
+    assert!(state.is_ejected(now));
+    assert_eq!(
+        state.eject(now, &config),
+        config.base_ejection_secs as u64 * 2
+    );
+    for _ in 0..10 {
+        state.eject(now, &config);
+    }
+    assert_eq!(state.eject(now, &config), config.max_ejection_secs as u64);
+}
+
+#[test]
+fn test_outlier_failure_ratio() {
+    let config = OutlierDetectionConfig {
+        consecutive_failures: 0,
+        failure_ratio: 0.5,
+        min_requests: 10,
+        ..OutlierDetectionConfig::default()
+    };
+    let mut state = OutlierState::default();
+    let now = NANOS_PER_SEC;
+    for i in 0..9 {
+        assert!(!state.record(i % 2 == 0, now, &config));
+    }
+    assert!(state.record(false, now, &config));
+
+    // the window starts over once it expires
+    let mut state = OutlierState::default();
+    for i in 0..9 {
+        state.record(i % 2 == 0, now, &config);
+    }
+    let later = now + (config.window_secs as i64 + 1) * NANOS_PER_SEC;
+    assert!(!state.record(false, later, &config));
+}
+
+#[test]
+fn test_outlier_max_ejection_percent() {
+    let config = OutlierDetectionConfig::default();
+    let now = NANOS_PER_SEC;
+    let mut targets_info = HashMap::new();
+    for i in 0..4 {
+        let target = Target::new(
+            format!("127.0.0.1:{}", 1081 + i),
+            100,
+            60,
+            true,
+            true,
+            1,
+            false,
+        );
+        targets_info.insert(i.to_string(), target);
+    }
+    assert!(ejection_allowed(&targets_info, now, &config));
+    targets_info
+        .get_mut("0")
+        .unwrap()
+        .target_outlier
+        .eject(now, &config);
+    targets_info
+        .get_mut("1")
+        .unwrap()
+        .target_outlier
+        .eject(now, &config);
+    assert!(!ejection_allowed(&targets_info, now, &config));
+}
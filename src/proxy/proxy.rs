@@ -1,152 +1,296 @@
+use chrono::Utc;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::error::Error;
+use std::io;
 use tokio;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
+use crate::proxy::balancer::{new_balancer, Balancer};
 use crate::proxy::config::read_config;
 use crate::proxy::config::Config;
-use crate::proxy::connection::{new_tunnel_id, NodeConnection, TargetConnection};
-use crate::proxy::g::{NODE_LOCAL_SELECTOR, SERVER_INFO};
-use crate::proxy::target::{calc_target_id_by_endpoint, dump_targets, Target, TargetDumpOrder};
-use log::{error, info};
+use crate::proxy::connection::{
+    abort_tunnel, new_tunnel_id, remove_tunnel, tunnel_idle_timeout_secs, wait_tunnel_close,
+    watch_tunnel, NodeConnection, TargetConnection, CLOSE_REASON_FINISHED,
+};
+use crate::proxy::forward::Forwarder;
+use crate::proxy::g::{NODE_LOCAL_SELECTOR, SERVER_INFO, TARGET_BACKUP_IN_USE};
+use crate::proxy::outlier::record_target_result;
+use crate::proxy::retry::RetryBudget;
+use crate::proxy::target::{
+    calc_target_id_by_endpoint, dump_targets, select_target_tier, update_target_connect_latency,
+    update_target_first_byte_latency, Target, TargetDump, TargetDumpOrder,
+};
+use log::{error, info, warn};
 use std::ops::Deref;
 
+// pause after a failed accept, e.g. when the fd limit is reached
+const ACCEPT_ERROR_BACKOFF_MS: u64 = 100;
+
 #[derive(Debug)]
 pub struct ProxyServer {
-    pub server_config: Config,
+    // swapped as a whole on reload, take a snapshot with config()
+    pub server_config: RwLock<Arc<Config>>,
+    pub balancer: Box<dyn Balancer>,
+    pub retry_budget: RetryBudget,
     pub targets_info: Arc<tokio::sync::Mutex<HashMap<String, Target>>>,
     pub tunnel_info: Arc<tokio::sync::Mutex<HashMap<String, (NodeConnection, TargetConnection)>>>,
+    // closed tunnels counted by close reason
+    pub tunnel_close_stats: Mutex<HashMap<String, u64>>,
 }
 
 impl ProxyServer {
     pub fn new() -> ProxyServer {
+        let server_config = read_config();
+        let balancer = new_balancer(&server_config.lb_node).unwrap_or_else(|e| panic!("{}", e));
+        let retry_budget = RetryBudget::new(server_config.lb_node.retry_budget.clone());
         ProxyServer {
-            server_config: read_config(),
+            server_config: RwLock::new(Arc::new(server_config)),
+            balancer,
+            retry_budget,
             targets_info: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
             tunnel_info: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            tunnel_close_stats: Mutex::new(HashMap::new()),
         }
     }
+
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.server_config.read().unwrap())
+    }
+
+    pub fn set_config(&self, config: Config) {
+        *self.server_config.write().unwrap() = Arc::new(config);
+    }
 }
 
-pub async fn connect_to_target_with_least_conn() -> (Option<tokio::net::TcpStream>, Option<Target>)
-{
-    let targets_dump = dump_targets(TargetDumpOrder::AscOrder).await;
+// the targets which may take a new connection, before picking the primary or backup tier
+fn eligible_targets(
+    targets: Vec<TargetDump>,
+    balancer: &dyn Balancer,
+    now: i64,
+    slow_start_secs: u32,
+) -> Vec<TargetDump> {
+    targets
+        .into_iter()
+        .map(|mut t| {
+            t.target.apply_slow_start(now, slow_start_secs);
+            t
+        })
+        .filter(|t| {
+            t.target.target_active
+                && !t.target.target_draining
+                && t.target.target_status
+                && !t.target.target_outlier.is_ejected(now)
+                && t.target_conn_count <= t.target.target_max_conn
+                && balancer.is_selectable(t)
+        })
+        .collect()
+}
+
+pub async fn connect_to_target(
+    node_remote_addr: &SocketAddr,
+) -> (Option<tokio::net::TcpStream>, Option<Target>) {
+    let server_config = SERVER_INFO.deref().config();
+    let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let targets_dump = eligible_targets(
+        dump_targets(TargetDumpOrder::NoOrder).await,
+        SERVER_INFO.deref().balancer.as_ref(),
+        now,
+        server_config.lb_node.slow_start_secs,
+    );
+    let (targets_dump, is_backup) = select_target_tier(targets_dump);
+    // with no target at all the tier in use stays as it was
+    if targets_dump.is_empty() {
+        warn!("no eligible target for {}", node_remote_addr);
+    } else if TARGET_BACKUP_IN_USE.swap(is_backup, Ordering::Relaxed) != is_backup {
+        if is_backup {
+            warn!("no primary target available, fail over to backup targets");
+        } else {
+            info!("primary targets available, fail back from backup targets");
+        }
+    }
+    let targets_dump = SERVER_INFO
+        .deref()
+        .balancer
+        .select(targets_dump, node_remote_addr);
 
     let mut tcp_stream_target: Option<tokio::net::TcpStream> = None;
     let mut conn_target_info: Option<Target> = None;
 
-    // try to connect to the target with the least connection
-    for t in targets_dump.iter() {
-        if !t.target.target_active {
-            continue;
-        }
-        if t.target_conn_count > t.target.target_max_conn {
-            continue;
-        }
+    let lb_node = &server_config.lb_node;
+    let connect_timeout = tokio::time::Duration::from_millis(lb_node.connect_timeout_ms as u64);
+    let connect_backoff = tokio::time::Duration::from_millis(lb_node.connect_backoff_ms as u64);
+    SERVER_INFO.deref().retry_budget.record_request();
 
-        let r = tokio::net::TcpSocket::new_v4();
+    // try to connect to the targets in the order chosen by the balancer
+    let mut attempts: u32 = 0;
+    for t in targets_dump.iter() {
+        let target_addr: SocketAddr = t.target.target_endpoint.parse().unwrap();
+        let r = if target_addr.is_ipv6() {
+            tokio::net::TcpSocket::new_v6()
+        } else {
+            tokio::net::TcpSocket::new_v4()
+        };
         let socket_conn = match r {
             Ok(s) => {
-                if SERVER_INFO
-                    .deref()
-                    .server_config
-                    .lb_node
-                    .enable_local_endpoints
-                    && SERVER_INFO
-                        .deref()
-                        .server_config
-                        .lb_node
-                        .local_endpoints
-                        .len()
-                        > 0
-                {
+                if lb_node.enable_local_endpoints && !lb_node.local_endpoints.is_empty() {
                     let u = NODE_LOCAL_SELECTOR.deref().fetch_add(1, Ordering::Relaxed);
-                    let local_socket_addr: SocketAddr =
-                        SERVER_INFO.deref().server_config.lb_node.local_endpoints[(u as usize
-                            % (SERVER_INFO
-                                .deref()
-                                .server_config
-                                .lb_node
-                                .local_endpoints
-                                .len()))]
-                        .parse()
-                        .expect(&*format!(
-                            "Invalid node local endpoint [{}]",
-                            SERVER_INFO.deref().server_config.lb_node.local_endpoints[(u as usize
-                                % (SERVER_INFO
-                                    .deref()
-                                    .server_config
-                                    .lb_node
-                                    .local_endpoints
-                                    .len()))]
-                        ));
-
-                    s.bind(local_socket_addr).expect(&*format!(
-                        "Bind node local endpoint [{}] fail",
-                        SERVER_INFO.deref().server_config.lb_node.local_endpoints[(u as usize
-                            % (SERVER_INFO
-                                .deref()
-                                .server_config
-                                .lb_node
-                                .local_endpoints
-                                .len()))]
-                    ));
+                    let local_socket_addr =
+                        match select_local_endpoint(&lb_node.local_endpoints, &target_addr, u) {
+                            Some(local_socket_addr) => local_socket_addr,
+                            None => {
+                                error!(
+                                    "no node local endpoint for the address family of target [{}]",
+                                    t.target.target_endpoint
+                                );
+                                continue;
+                            }
+                        };
+                    if let Err(e) = s.bind(local_socket_addr) {
+                        error!(
+                            "bind node local endpoint [{}] failed; err = {:?}",
+                            local_socket_addr, e
+                        );
+                        continue;
+                    }
                 }
-                Some(s)
+                s
             }
             Err(_) => continue,
         };
-        let connect_timeout = tokio::time::Duration::from_secs(5);
-        if let Ok(r) = tokio::time::timeout(
-            connect_timeout,
-            socket_conn
-                .unwrap()
-                .connect(t.target.target_endpoint.parse().unwrap()),
-        )
-        .await
+
+        // only the connects actually made count against the attempts and the retry budget
+        if lb_node.max_connect_attempts > 0 && attempts >= lb_node.max_connect_attempts {
+            warn!(
+                "give up connecting for {} after {} attempts",
+                node_remote_addr, attempts
+            );
+            break;
+        }
+        if attempts > 0 {
+            if !SERVER_INFO.deref().retry_budget.try_acquire_retry() {
+                warn!(
+                    "give up connecting for {}, retry budget exhausted",
+                    node_remote_addr
+                );
+                break;
+            }
+            if !connect_backoff.is_zero() {
+                tokio::time::sleep(connect_backoff).await;
+            }
+        }
+        attempts += 1;
+
+        let target_id = calc_target_id_by_endpoint(t.target.target_endpoint.clone());
+        let connect_start = tokio::time::Instant::now();
+        if let Ok(r) = tokio::time::timeout(connect_timeout, socket_conn.connect(target_addr)).await
         {
             tcp_stream_target = match r {
                 Ok(c) => Some(c),
-                Err(_) => continue,
+                Err(e) => {
+                    // a refused connect is as bad as a timed out one
+                    update_target_connect_latency(&target_id, connect_timeout).await;
+                    record_target_result(&target_id, false).await;
+                    error!(
+                        "connect to target [{}] failed; err = {:?}",
+                        t.target.target_endpoint, e
+                    );
+                    continue;
+                }
             };
+            update_target_connect_latency(&target_id, connect_start.elapsed()).await;
+            record_target_result(&target_id, true).await;
 
             conn_target_info = Some(t.target.clone());
             break;
         } else {
+            update_target_connect_latency(&target_id, connect_timeout).await;
+            record_target_result(&target_id, false).await;
+            error!("connect to target [{}] timeout", t.target.target_endpoint);
             continue;
         }
     }
 
-    return (tcp_stream_target, conn_target_info);
+    (tcp_stream_target, conn_target_info)
+}
+
+// the n-th of the local endpoints in the address family of the target
+pub fn select_local_endpoint(
+    local_endpoints: &[String],
+    target_addr: &SocketAddr,
+    n: u64,
+) -> Option<SocketAddr> {
+    let local_addrs: Vec<SocketAddr> = local_endpoints
+        .iter()
+        .filter_map(|e| e.parse::<SocketAddr>().ok())
+        .filter(|e| e.is_ipv6() == target_addr.is_ipv6())
+        .collect();
+    if local_addrs.is_empty() {
+        return None;
+    }
+    Some(local_addrs[n as usize % local_addrs.len()])
+}
+
+// an ipv6 listener takes ipv4 clients as well unless ipv6_only is set
+pub fn bind_listener(addr: SocketAddr, ipv6_only: bool) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
 }
 
 pub async fn start_tcp_proxy_server() -> Result<(), Box<dyn Error>> {
-    let node_listener =
-        tokio::net::TcpListener::bind(SERVER_INFO.deref().server_config.lb_node.listen.as_str())
-            .await
-            .expect(
-                format!(
-                    "Failure binding node listen endpoint [{}]",
-                    SERVER_INFO.deref().server_config.lb_node.listen
-                )
-                .as_str(),
-            );
+    // the listen endpoint can not be changed by reload
+    let server_config = SERVER_INFO.deref().config();
+    let node_listen = server_config.lb_node.listen.clone();
+    let node_listen_addr: SocketAddr = node_listen
+        .parse()
+        .map_err(|e| format!("Invalid node listen endpoint [{}]: {}", node_listen, e))?;
+    let node_listener = bind_listener(node_listen_addr, server_config.lb_node.ipv6_only)
+        .and_then(tokio::net::TcpListener::from_std)
+        .map_err(|e| {
+            format!(
+                "Failure binding node listen endpoint [{}]: {}",
+                node_listen, e
+            )
+        })?;
 
     loop {
-        let (mut tcp_stream_node, node_remote_addr) = node_listener.accept().await?;
+        // only the startup is fatal, a failed accept must not take the live tunnels down
+        let (mut tcp_stream_node, node_remote_addr) = match node_listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("accept failed on [{}]; err = {:?}", node_listen, e);
+                tokio::time::sleep(tokio::time::Duration::from_millis(ACCEPT_ERROR_BACKOFF_MS))
+                    .await;
+                continue;
+            }
+        };
+        // ipv4 clients of a dual-stack listener arrive as ::ffff:a.b.c.d
+        let node_remote_addr = SocketAddr::new(
+            node_remote_addr.ip().to_canonical(),
+            node_remote_addr.port(),
+        );
         info!("remote connection from {}", node_remote_addr);
+        let server_config = SERVER_INFO.deref().config();
 
         let node_connection_count = SERVER_INFO.deref().tunnel_info.lock().await.len() as u32;
-        if node_connection_count > SERVER_INFO.deref().server_config.lb_node.max_conn {
+        if node_connection_count > server_config.lb_node.max_conn {
             let _ = tcp_stream_node.shutdown().await;
             continue;
         }
 
-        let (tcp_stream_target, conn_target_info) = connect_to_target_with_least_conn().await;
+        let (tcp_stream_target, conn_target_info) = connect_to_target(&node_remote_addr).await;
 
         match tcp_stream_target {
             Some(_) => (),
@@ -156,8 +300,12 @@ pub async fn start_tcp_proxy_server() -> Result<(), Box<dyn Error>> {
             }
         }
 
-        let node_timeout = SERVER_INFO.deref().server_config.lb_node.timeout.clone();
-        let target_timeout = conn_target_info.clone().unwrap().target_timeout.clone();
+        let idle_timeout_secs = tunnel_idle_timeout_secs(
+            server_config.lb_node.timeout,
+            conn_target_info.clone().unwrap().target_timeout,
+        );
+        let write_timeout =
+            tokio::time::Duration::from_millis(server_config.lb_node.write_timeout_ms as u64);
 
         let conn_target_id =
             calc_target_id_by_endpoint(conn_target_info.clone().unwrap().target_endpoint);
@@ -168,22 +316,46 @@ pub async fn start_tcp_proxy_server() -> Result<(), Box<dyn Error>> {
         let (mut tcp_stream_target_read, mut tcp_stream_target_write) =
             tcp_stream_target.into_split();
 
-        let node_connection_info = NodeConnection::new(
-            SERVER_INFO.deref().server_config.lb_node.listen.clone(),
-            node_remote_addr.to_string(),
-        );
+        let node_connection_info =
+            NodeConnection::new(node_listen.clone(), node_remote_addr.to_string());
 
+        let target_connected_at = tokio::time::Instant::now();
+        let conn_target_id_dump = conn_target_id.clone();
         let target_connection_info = TargetConnection::new(
             target_local_addr.clone(),
             conn_target_info.clone().unwrap().target_endpoint,
-            conn_target_id,
+            conn_target_id.clone(),
         );
 
         let tunnel_id = new_tunnel_id();
-        let tunnel_info_arc = Arc::clone(&SERVER_INFO.deref().tunnel_info);
         let tunnel_id_dump = tunnel_id.clone();
-        let tunnel_info_arc_dump = Arc::clone(&tunnel_info_arc);
 
+        // the copy tasks own the counters, the registry is only locked at open and close
+        let node_traffic = Arc::clone(&node_connection_info.connection.traffic);
+        let target_traffic = Arc::clone(&target_connection_info.connection.traffic);
+        let node_traffic_dump = Arc::clone(&node_traffic);
+        let target_traffic_dump = Arc::clone(&target_traffic);
+        let buffer_size = server_config.lb_node.buffer_size as usize;
+        let forwarding = server_config.lb_node.forwarding.clone();
+        let forwarding_dump = forwarding.clone();
+        // set by the first direction to see eof, the second one removes the tunnel
+        let half_closed = Arc::new(AtomicBool::new(false));
+        let half_closed_dump = Arc::clone(&half_closed);
+
+        // the watchdog ends once both copy tasks dropped their alive_tx
+        let (alive_tx, alive_rx) = mpsc::channel::<()>(1);
+        let alive_tx_dump = alive_tx.clone();
+        tokio::spawn(watch_tunnel(
+            tunnel_id.clone(),
+            [Arc::clone(&node_traffic), Arc::clone(&target_traffic)],
+            node_connection_info.connection.create_time,
+            idle_timeout_secs,
+            server_config.lb_node.max_tunnel_lifetime_secs,
+            alive_rx,
+        ));
+
+        let node_close_rx = target_connection_info.close_tx.subscribe();
+        let target_close_rx = target_connection_info.close_tx.subscribe();
         SERVER_INFO.deref().tunnel_info.lock().await.insert(
             tunnel_id.clone(),
             (node_connection_info, target_connection_info),
@@ -192,54 +364,39 @@ pub async fn start_tcp_proxy_server() -> Result<(), Box<dyn Error>> {
             "build tunnel |{}| successfully, node: {}->{}, target: {}->{}",
             tunnel_id,
             node_remote_addr.to_string(),
-            SERVER_INFO.deref().server_config.lb_node.listen.clone(),
+            node_listen.clone(),
             target_local_addr.clone(),
             conn_target_info.clone().unwrap().target_endpoint
         );
 
         // task of reading from node connection and then writing to target connection
         tokio::spawn(async move {
-            let mut buf = [0; 1024];
-            let mut count;
-            loop {
-                let read_timeout = tokio::time::Duration::from_secs(node_timeout as u64);
-                if let Ok(r) =
-                    tokio::time::timeout(read_timeout, tcp_stream_node_read.read(&mut buf)).await
-                {
-                    match r {
-                        Ok(n) if n == 0 => {
-                            tunnel_info_arc.lock().await.remove(&tunnel_id);
+            let _alive_tx = alive_tx;
+            let forward = async {
+                let mut forwarder = Forwarder::new(&forwarding, buffer_size);
+                let mut count;
+                loop {
+                    // silence is left to the idle watchdog
+                    match forwarder.read(&mut tcp_stream_node_read).await {
+                        Ok(0) => {
+                            // pass the half close on, the other direction keeps running
+                            let _ = tokio::time::timeout(
+                                write_timeout,
+                                tcp_stream_target_write.shutdown(),
+                            )
+                            .await;
+                            if half_closed.swap(true, Ordering::SeqCst) {
+                                remove_tunnel(&tunnel_id, CLOSE_REASON_FINISHED).await;
+                            }
                             info!("|{}| tcp_stream_node_read: closed by remote", tunnel_id);
                             return;
                         }
                         Ok(n) => {
                             count = n;
-                            let tunnel_info = tunnel_info_arc.lock().await;
-                            let v = tunnel_info.get(&tunnel_id);
-                            match v {
-                                Some((node_info, target_info)) => {
-                                    let mut node_info_dump = node_info.clone();
-                                    let target_info_dump = target_info.clone();
-                                    node_info_dump.add_read_n(n as u64);
-                                    drop(tunnel_info);
-                                    tunnel_info_arc.lock().await.insert(
-                                        tunnel_id.clone(),
-                                        (node_info_dump, target_info_dump),
-                                    );
-                                }
-                                None => {
-                                    drop(tunnel_info);
-                                    tunnel_info_arc.lock().await.remove(&tunnel_id);
-                                    error!(
-                                        "|{}| tcp_stream_node_read: not find, disconnect",
-                                        tunnel_id
-                                    );
-                                    return;
-                                }
-                            }
+                            node_traffic.add_read_n(n as u64);
                         }
                         Err(e) => {
-                            tunnel_info_arc.lock().await.remove(&tunnel_id.clone());
+                            abort_tunnel(&tunnel_id, "node read failed").await;
                             error!(
                                 "|{}| tcp_stream_node_read: failed to read from socket; err = {:?}",
                                 tunnel_id, e
@@ -247,73 +404,62 @@ pub async fn start_tcp_proxy_server() -> Result<(), Box<dyn Error>> {
                             return;
                         }
                     };
-                } else {
-                    // read from node timeout
-                    tunnel_info_arc.lock().await.remove(&tunnel_id);
-                    error!("|{}| tcp_stream_node_read: timeout", tunnel_id);
-                    return;
-                }
 
-                let write_timeout = tokio::time::Duration::from_secs(target_timeout as u64);
-                if let Ok(r) = tokio::time::timeout(
-                    write_timeout,
-                    tcp_stream_target_write.write_all(&buf[0..count]),
-                )
-                .await
-                {
-                    match r {
-                        Ok(_) => {
-                            let tunnel_info = tunnel_info_arc.lock().await;
-                            let v = tunnel_info.get(&tunnel_id);
-                            match v {
-                                Some((node_info, target_info)) => {
-                                    let node_info_dump = node_info.clone();
-                                    let mut target_info_dump = target_info.clone();
-                                    target_info_dump.add_write_n(count as u64);
-                                    drop(tunnel_info);
-                                    tunnel_info_arc.lock().await.insert(
-                                        tunnel_id.clone(),
-                                        (node_info_dump, target_info_dump),
-                                    );
-                                }
-                                None => {
-                                    drop(tunnel_info);
-                                    tunnel_info_arc.lock().await.remove(&tunnel_id);
-                                    error!(
-                                        "|{}| tcp_stream_target_write: not find, disconnect",
-                                        tunnel_id
-                                    );
-                                    return;
-                                }
+                    if let Ok(r) = tokio::time::timeout(
+                        write_timeout,
+                        forwarder.write(&mut tcp_stream_target_write, count),
+                    )
+                    .await
+                    {
+                        match r {
+                            Ok(_) => {
+                                target_traffic.add_write_n(count as u64);
+                            }
+                            Err(e) => {
+                                abort_tunnel(&tunnel_id, "target write failed").await;
+                                record_target_result(&conn_target_id, false).await;
+                                error!("|{}| tcp_stream_target_write: failed to write to socket; err = {:?}", tunnel_id, e);
+                                return;
                             }
                         }
-                        Err(e) => {
-                            tunnel_info_arc.lock().await.remove(&tunnel_id);
-                            error!("|{}| tcp_stream_target_write: failed to write to socket; err = {:?}", tunnel_id, e);
-                            return;
-                        }
+                    } else {
+                        // write to target stalled
+                        abort_tunnel(&tunnel_id, "target write timeout").await;
+                        record_target_result(&conn_target_id, false).await;
+                        error!("|{}| tcp_stream_target_write: timeout", tunnel_id);
+                        return;
                     }
-                } else {
-                    // write to target timeout
-                    tunnel_info_arc.lock().await.remove(&tunnel_id);
-                    error!("|{}| tcp_stream_target_write: timeout", tunnel_id);
-                    return;
+                }
+            };
+            tokio::select! {
+                _ = forward => {}
+                reason = wait_tunnel_close(node_close_rx) => {
+                    remove_tunnel(&tunnel_id, &reason).await;
+                    info!("|{}| tcp_stream_node_read: closed, {}", tunnel_id, reason);
                 }
             }
         });
 
         // task of reading from target connection and then writing to node connection
         tokio::spawn(async move {
-            let mut buf = [0; 1024];
-            let mut count;
-            loop {
-                let read_timeout = tokio::time::Duration::from_secs(target_timeout as u64);
-                if let Ok(r) =
-                    tokio::time::timeout(read_timeout, tcp_stream_target_read.read(&mut buf)).await
-                {
-                    match r {
-                        Ok(n) if n == 0 => {
-                            tunnel_info_arc_dump.lock().await.remove(&tunnel_id_dump);
+            let _alive_tx = alive_tx_dump;
+            let forward = async {
+                let mut forwarder = Forwarder::new(&forwarding_dump, buffer_size);
+                let mut count;
+                let mut first_byte = true;
+                loop {
+                    // silence is left to the idle watchdog
+                    match forwarder.read(&mut tcp_stream_target_read).await {
+                        Ok(0) => {
+                            // pass the half close on, the other direction keeps running
+                            let _ = tokio::time::timeout(
+                                write_timeout,
+                                tcp_stream_node_write.shutdown(),
+                            )
+                            .await;
+                            if half_closed_dump.swap(true, Ordering::SeqCst) {
+                                remove_tunnel(&tunnel_id_dump, CLOSE_REASON_FINISHED).await;
+                            }
                             info!(
                                 "|{}| tcp_stream_target_read: closed by remote",
                                 tunnel_id_dump
@@ -322,92 +468,111 @@ pub async fn start_tcp_proxy_server() -> Result<(), Box<dyn Error>> {
                         }
                         Ok(n) => {
                             count = n;
-                            let tunnel_info = tunnel_info_arc_dump.lock().await;
-                            let v = tunnel_info.get(&tunnel_id_dump);
-                            match v {
-                                Some((node_info, target_info)) => {
-                                    let node_info_dump = node_info.clone();
-                                    let mut target_info_dump = target_info.clone();
-                                    target_info_dump.add_read_n(n as u64);
-                                    drop(tunnel_info);
-                                    tunnel_info_arc_dump.lock().await.insert(
-                                        tunnel_id_dump.clone(),
-                                        (node_info_dump, target_info_dump),
-                                    );
-                                }
-                                None => {
-                                    drop(tunnel_info);
-                                    tunnel_info_arc_dump.lock().await.remove(&tunnel_id_dump);
-                                    error!(
-                                        "|{}| tcp_stream_target_read: not find, disconnect",
-                                        tunnel_id_dump
-                                    );
-                                    return;
-                                }
+                            if first_byte {
+                                first_byte = false;
+                                update_target_first_byte_latency(
+                                    &conn_target_id_dump,
+                                    target_connected_at.elapsed(),
+                                )
+                                .await;
                             }
+                            target_traffic_dump.add_read_n(n as u64);
                         }
                         Err(e) => {
-                            tunnel_info_arc_dump.lock().await.remove(&tunnel_id_dump);
+                            abort_tunnel(&tunnel_id_dump, "target read failed").await;
+                            record_target_result(&conn_target_id_dump, false).await;
                             error!("|{}| tcp_stream_target_read: failed to read from socket; err = {:?}", tunnel_id_dump, e);
                             return;
                         }
                     };
-                } else {
-                    // read from target timeout
-                    tunnel_info_arc_dump.lock().await.remove(&tunnel_id_dump);
-                    error!("|{}| tcp_stream_target_read: timeout", tunnel_id_dump);
-                    return;
-                }
 
-                let write_timeout = tokio::time::Duration::from_secs(node_timeout as u64);
-                if let Ok(r) = tokio::time::timeout(
-                    write_timeout,
-                    tcp_stream_node_write.write_all(&buf[0..count]),
-                )
-                .await
-                {
-                    match r {
-                        Ok(_) => {
-                            let tunnel_info = tunnel_info_arc_dump.lock().await;
-                            let v = tunnel_info.get(&tunnel_id_dump);
-                            match v {
-                                Some((node_info, target_info)) => {
-                                    let mut node_info_dump = node_info.clone();
-                                    let target_info_dump = target_info.clone();
-                                    node_info_dump.add_write_n(count as u64);
-                                    drop(tunnel_info);
-                                    tunnel_info_arc_dump.lock().await.insert(
-                                        tunnel_id_dump.clone(),
-                                        (node_info_dump, target_info_dump),
-                                    );
-                                }
-                                None => {
-                                    drop(tunnel_info);
-                                    tunnel_info_arc_dump.lock().await.remove(&tunnel_id_dump);
-                                    error!(
-                                        "|{}| tcp_stream_node_write: not find, disconnect",
-                                        tunnel_id_dump
-                                    );
-                                    return;
-                                }
+                    if let Ok(r) = tokio::time::timeout(
+                        write_timeout,
+                        forwarder.write(&mut tcp_stream_node_write, count),
+                    )
+                    .await
+                    {
+                        match r {
+                            Ok(_) => {
+                                node_traffic_dump.add_write_n(count as u64);
+                            }
+                            Err(e) => {
+                                abort_tunnel(&tunnel_id_dump, "node write failed").await;
+                                error!(
+                                    "|{}| tcp_stream_node_write: failed to write to socket; err = {:?}",
+                                    tunnel_id_dump, e
+                                );
+                                return;
                             }
                         }
-                        Err(e) => {
-                            tunnel_info_arc_dump.lock().await.remove(&tunnel_id_dump);
-                            error!(
-                                "|{}| tcp_stream_node_write: failed to write to socket; err = {:?}",
-                                tunnel_id_dump, e
-                            );
-                            return;
-                        }
+                    } else {
+                        // write to node stalled
+                        abort_tunnel(&tunnel_id_dump, "node write timeout").await;
+                        error!("|{}| tcp_stream_node_write: timeout", tunnel_id_dump);
+                        return;
                     }
-                } else {
-                    // write to node timeout
-                    tunnel_info_arc_dump.lock().await.remove(&tunnel_id_dump);
-                    error!("|{}| tcp_stream_node_write: timeout", tunnel_id_dump);
-                    return;
+                }
+            };
+            tokio::select! {
+                _ = forward => {}
+                reason = wait_tunnel_close(target_close_rx) => {
+                    remove_tunnel(&tunnel_id_dump, &reason).await;
+                    info!("|{}| tcp_stream_target_read: closed, {}", tunnel_id_dump, reason);
                 }
             }
         });
     }
 }
+
+#[test]
+fn test_select_local_endpoint() {
+    let local_endpoints: Vec<String> = ["10.0.0.1:0", "[fd00::1]:0", "10.0.0.2:0", "bad"]
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+    let target_v4: SocketAddr = "192.168.1.10:8080".parse().unwrap();
+    let target_v6: SocketAddr = "[fd00::10]:8080".parse().unwrap();
+
+    let picked: Vec<String> = (0..3)
+        .map(|n| {
+            select_local_endpoint(&local_endpoints, &target_v4, n)
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    assert_eq!(picked, vec!["10.0.0.1:0", "10.0.0.2:0", "10.0.0.1:0"]);
+    assert_eq!(
+        select_local_endpoint(&local_endpoints, &target_v6, 1)
+            .unwrap()
+            .to_string(),
+        "[fd00::1]:0"
+    );
+    assert_eq!(
+        select_local_endpoint(&local_endpoints[..1], &target_v6, 0),
+        None
+    );
+}
+
+#[test]
+fn test_zero_weight_primaries_fail_over() {
+    use crate::proxy::balancer::{LeastConnBalancer, WeightedRoundRobinBalancer};
+
+    let primary = TargetDump::new("127.0.0.1:1081".to_string(), 100, 0, 60, true, true, 0);
+    let mut backup = TargetDump::new("127.0.0.1:1082".to_string(), 100, 0, 60, true, true, 1);
+    backup.target.target_backup = true;
+    let targets = vec![primary, backup];
+
+    // a primary with zero weight takes nothing under weighted round-robin, so the backups do
+    let balancer = WeightedRoundRobinBalancer::new();
+    let (tier, is_backup) = select_target_tier(eligible_targets(targets.clone(), &balancer, 0, 0));
+    assert!(is_backup);
+    assert_eq!(tier[0].target.target_endpoint, "127.0.0.1:1082");
+    let client_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+    assert_eq!(balancer.select(tier, &client_addr).len(), 1);
+
+    // the other strategies ignore the weight
+    let balancer = LeastConnBalancer::new();
+    let (tier, is_backup) = select_target_tier(eligible_targets(targets, &balancer, 0, 0));
+    assert!(!is_backup);
+    assert_eq!(tier[0].target.target_endpoint, "127.0.0.1:1081");
+}
@@ -0,0 +1,118 @@
+use crate::proxy::config::{load_config, Config};
+use crate::proxy::g::{CONFIG_RELOAD_LOCK, SERVER_INFO};
+use crate::proxy::target::{sync_targets_from_config, TargetSyncResult};
+#[cfg(unix)]
+use log::error;
+use log::{info, warn};
+use std::error::Error;
+use std::ops::Deref;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+// settings bound at startup keep their old value, return the names of the changed ones
+fn keep_restart_only_fields(old_config: &Config, new_config: &mut Config) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    if new_config.lb_log.log_set_level != old_config.lb_log.log_set_level {
+        new_config.lb_log.log_set_level = old_config.lb_log.log_set_level.clone();
+        changed.push("lb_log.log_set_level");
+    }
+    if new_config.lb_node.listen != old_config.lb_node.listen {
+        new_config.lb_node.listen = old_config.lb_node.listen.clone();
+        changed.push("lb_node.listen");
+    }
+    if new_config.lb_node.ipv6_only != old_config.lb_node.ipv6_only {
+        new_config.lb_node.ipv6_only = old_config.lb_node.ipv6_only;
+        changed.push("lb_node.ipv6_only");
+    }
+    if new_config.lb_node.balance != old_config.lb_node.balance {
+        new_config.lb_node.balance = old_config.lb_node.balance.clone();
+        changed.push("lb_node.balance");
+    }
+    if new_config.lb_node.hash_key != old_config.lb_node.hash_key {
+        new_config.lb_node.hash_key = old_config.lb_node.hash_key.clone();
+        changed.push("lb_node.hash_key");
+    }
+    if new_config.lb_node.retry_budget.retry_ratio != old_config.lb_node.retry_budget.retry_ratio
+        || new_config.lb_node.retry_budget.min_retries_per_sec
+            != old_config.lb_node.retry_budget.min_retries_per_sec
+    {
+        new_config.lb_node.retry_budget = old_config.lb_node.retry_budget.clone();
+        changed.push("lb_node.retry_budget");
+    }
+    if new_config.lb_api.listen != old_config.lb_api.listen {
+        new_config.lb_api.listen = old_config.lb_api.listen.clone();
+        changed.push("lb_api.listen");
+    }
+    changed
+}
+
+// an invalid config is rejected as a whole and the running one stays in effect
+pub async fn reload_config() -> Result<TargetSyncResult, String> {
+    let _reload_guard = CONFIG_RELOAD_LOCK.lock().await;
+
+    let mut new_config = load_config()?;
+    new_config.check().map_err(|e| e.to_string())?;
+
+    let old_config = SERVER_INFO.deref().config();
+    for field in keep_restart_only_fields(&old_config, &mut new_config) {
+        warn!(
+            "config reload: [{}] changed, takes effect after restart",
+            field
+        );
+    }
+
+    let result = sync_targets_from_config(&new_config.lb_targets).await;
+    SERVER_INFO.deref().set_config(new_config);
+    info!(
+        "config reloaded, targets added: {:?}, updated: {:?}, removed: {:?}",
+        result.added, result.updated, result.removed
+    );
+    Ok(result)
+}
+
+#[cfg(unix)]
+pub async fn start_reload_signal_loop() -> Result<(), Box<dyn Error>> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    loop {
+        sighup.recv().await;
+        info!("received SIGHUP, reloading config");
+        if let Err(e) = reload_config().await {
+            error!("config reload failed, keep the running config: {}", e);
+        }
+    }
+}
+
+// without SIGHUP the config is only reloaded through /api/reload_config
+#[cfg(not(unix))]
+pub async fn start_reload_signal_loop() -> Result<(), Box<dyn Error>> {
+    info!("no reload signal on this platform, reload through the api");
+    Ok(())
+}
+
+#[test]
+fn test_keep_restart_only_fields() {
+    let old_config: Config = serde_json::from_str(
+        r#"{
+            "lb_log": {"log_set_level": "info"},
+            "lb_node": {"listen": "0.0.0.0:8080", "max_conn": 100, "timeout": 60,
+                "enable_local_endpoints": false, "local_endpoints": []},
+            "lb_targets": [],
+            "lb_api": {"listen": "0.0.0.0:9000"}
+        }"#,
+    )
+    .unwrap();
+
+    let mut new_config = old_config.clone();
+    new_config.lb_node.max_conn = 200;
+    assert!(keep_restart_only_fields(&old_config, &mut new_config).is_empty());
+
+    new_config.lb_node.listen = "0.0.0.0:8081".to_string();
+    new_config.lb_node.balance = "random".to_string();
+    assert_eq!(
+        keep_restart_only_fields(&old_config, &mut new_config),
+        vec!["lb_node.listen", "lb_node.balance"]
+    );
+    assert_eq!(new_config.lb_node.listen, "0.0.0.0:8080");
+    assert_eq!(new_config.lb_node.balance, old_config.lb_node.balance);
+    assert_eq!(new_config.lb_node.max_conn, 200);
+}
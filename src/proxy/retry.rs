@@ -0,0 +1,78 @@
+use crate::proxy::config::RetryBudgetConfig;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const RETRY_BUDGET_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct RetryBudgetWindow {
+    start: Instant,
+    requests: u32,
+    retries: u32,
+}
+
+// limits the connect retries to a share of the accepted connections, so a backend
+// outage does not turn every accept into a connect attempt against every target
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    window: Mutex<RetryBudgetWindow>,
+}
+
+impl RetryBudget {
+    pub fn new(config: RetryBudgetConfig) -> RetryBudget {
+        RetryBudget {
+            config,
+            window: Mutex::new(RetryBudgetWindow {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    fn current_window(&self) -> std::sync::MutexGuard<'_, RetryBudgetWindow> {
+        let mut window = self.window.lock().unwrap();
+        if window.start.elapsed() >= RETRY_BUDGET_WINDOW {
+            window.start = Instant::now();
+            window.requests = 0;
+            window.retries = 0;
+        }
+        window
+    }
+
+    pub fn record_request(&self) {
+        self.current_window().requests += 1;
+    }
+
+    pub fn try_acquire_retry(&self) -> bool {
+        let mut window = self.current_window();
+        let allowed = (self.config.retry_ratio * window.requests as f64) as u32;
+        if window.retries >= allowed.max(self.config.min_retries_per_sec) {
+            return false;
+        }
+        window.retries += 1;
+        true
+    }
+}
+
+#[test]
+fn test_retry_budget() {
+    let budget = RetryBudget::new(RetryBudgetConfig {
+        retry_ratio: 0.5,
+        min_retries_per_sec: 2,
+    });
+    // the minimum is available without any request
+    assert!(budget.try_acquire_retry());
+    assert!(budget.try_acquire_retry());
+    assert!(!budget.try_acquire_retry());
+
+    // 10 requests allow 5 retries in the window, 2 are used already
+    for _ in 0..10 {
+        budget.record_request();
+    }
+    for _ in 0..3 {
+        assert!(budget.try_acquire_retry());
+    }
+    assert!(!budget.try_acquire_retry());
+}
@@ -1,8 +1,20 @@
 use md5;
 
-use crate::proxy::connection::get_target_conn_count_by_target_id;
+use crate::proxy::config::{HealthCheckConfig, TargetConfig};
+use crate::proxy::connection::{close_tunnels, get_targets_tunnel_stat};
 use crate::proxy::g::SERVER_INFO;
+use crate::proxy::outlier::OutlierState;
+use chrono::Utc;
+use log::warn;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
+use std::time::Duration;
+
+// weight of the newest sample in the latency moving averages
+const LATENCY_EWMA_ALPHA: f64 = 0.2;
+// capacity of a target at the very start of its slow start window
+const SLOW_START_MIN_FACTOR: f64 = 0.1;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
@@ -19,6 +31,25 @@ pub struct Target {
     pub target_status: bool,
     pub target_max_conn: u32,
     pub target_timeout: u32,
+    pub target_weight: u32,
+    pub target_backup: bool,
+    // moving averages in microseconds, 0 means not measured yet
+    pub target_connect_latency: u64,
+    pub target_first_byte_latency: u64,
+    pub target_health_check: Option<HealthCheckConfig>,
+    // consecutive health check results, reset by the opposite result
+    pub target_health_success: u32,
+    pub target_health_failure: u32,
+    pub target_health_last_check: i64,
+    // a check is in flight, the next one waits for its result
+    pub target_health_checking: bool,
+    pub target_outlier: OutlierState,
+    // when the target came back into service, 0 if it is not ramping up
+    pub target_slow_start_begin: i64,
+    // a draining target takes no new connections while its tunnels finish
+    pub target_draining: bool,
+    // when the remaining tunnels are force closed, 0 means never
+    pub target_drain_deadline: i64,
 }
 
 impl Target {
@@ -28,6 +59,8 @@ impl Target {
         target_timeout: u32,
         target_active: bool,
         target_status: bool,
+        target_weight: u32,
+        target_backup: bool,
     ) -> Target {
         Target {
             target_endpoint,
@@ -35,39 +68,222 @@ impl Target {
             target_status,
             target_max_conn,
             target_timeout,
+            target_weight,
+            target_backup,
+            target_connect_latency: 0,
+            target_first_byte_latency: 0,
+            target_health_check: None,
+            target_health_success: 0,
+            target_health_failure: 0,
+            target_health_last_check: 0,
+            target_health_checking: false,
+            target_outlier: OutlierState::default(),
+            target_slow_start_begin: 0,
+            target_draining: false,
+            target_drain_deadline: 0,
         }
     }
-}
 
-pub fn calc_target_id_by_endpoint(endpoint: String) -> String {
-    let digest = md5::compute(endpoint.as_str());
-    format!("{:x}", digest).to_string()
-}
+    // the health check to run now, None if it is not due yet or the previous one is still running
+    pub fn start_health_check(&mut self, now: i64) -> Option<HealthCheckConfig> {
+        let health_check = self.target_health_check.as_ref()?;
+        if self.target_health_checking
+            || now - self.target_health_last_check < health_check.interval_ms as i64 * 1_000_000
+        {
+            return None;
+        }
+        self.target_health_last_check = now;
+        self.target_health_checking = true;
+        Some(health_check.clone())
+    }
 
-pub async fn init_targets_from_config() {
-    for target_config in SERVER_INFO.deref().server_config.lb_targets.iter() {
-        let target = Target::new(
+    // count the health check result, return true if the health status flipped
+    pub fn apply_health_check_result(&mut self, healthy: bool) -> bool {
+        self.target_health_checking = false;
+        let (rise, fall) = match &self.target_health_check {
+            Some(health_check) => (health_check.rise, health_check.fall),
+            None => return false,
+        };
+        if healthy {
+            self.target_health_success += 1;
+            self.target_health_failure = 0;
+            if !self.target_status && self.target_health_success >= rise {
+                self.target_status = true;
+                self.target_slow_start_begin = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+                return true;
+            }
+        } else {
+            self.target_health_failure += 1;
+            self.target_health_success = 0;
+            if self.target_status && self.target_health_failure >= fall {
+                self.target_status = false;
+                return true;
+            }
+        }
+        false
+    }
+
+    // share of the capacity a recovered target may take, ramps up linearly to 1
+    pub fn slow_start_factor(&self, now: i64, slow_start_secs: u32) -> f64 {
+        if slow_start_secs == 0 || self.target_slow_start_begin == 0 {
+            return 1.0;
+        }
+        let elapsed = (now - self.target_slow_start_begin) as f64 / 1_000_000_000.0;
+        (elapsed / slow_start_secs as f64).clamp(SLOW_START_MIN_FACTOR, 1.0)
+    }
+
+    // scale max_conn and weight by the slow start factor
+    pub fn apply_slow_start(&mut self, now: i64, slow_start_secs: u32) {
+        let factor = self.slow_start_factor(now, slow_start_secs);
+        if factor >= 1.0 {
+            return;
+        }
+        self.target_max_conn = ((self.target_max_conn as f64 * factor).ceil() as u32).max(1);
+        if self.target_weight > 0 {
+            self.target_weight = ((self.target_weight as f64 * factor).ceil() as u32).max(1);
+        }
+    }
+
+    pub fn from_config(target_config: &TargetConfig) -> Target {
+        let mut target = Target::new(
             target_config.target_endpoint.clone(),
             target_config.target_max_conn,
             target_config.target_timeout,
             target_config.target_active,
             true,
+            target_config.target_weight,
+            target_config.target_backup,
         );
+        target.target_health_check = target_config.target_health_check.clone();
+        target
+    }
 
-        SERVER_INFO.deref().targets_info.lock().await.insert(
+    // apply the configured settings, return true if anything changed
+    pub fn update_from_config(&mut self, target_config: &TargetConfig) -> bool {
+        let mut changed = false;
+        if self.target_max_conn != target_config.target_max_conn {
+            self.target_max_conn = target_config.target_max_conn;
+            changed = true;
+        }
+        if self.target_timeout != target_config.target_timeout {
+            self.target_timeout = target_config.target_timeout;
+            changed = true;
+        }
+        if self.target_active != target_config.target_active {
+            if target_config.target_active {
+                self.target_slow_start_begin = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+            }
+            self.target_active = target_config.target_active;
+            changed = true;
+        }
+        if self.target_weight != target_config.target_weight {
+            self.target_weight = target_config.target_weight;
+            changed = true;
+        }
+        if self.target_backup != target_config.target_backup {
+            self.target_backup = target_config.target_backup;
+            changed = true;
+        }
+        if self.target_health_check != target_config.target_health_check {
+            // a target without health check is always considered healthy
+            if target_config.target_health_check.is_none() {
+                self.target_status = true;
+            }
+            self.target_health_check = target_config.target_health_check.clone();
+            self.target_health_success = 0;
+            self.target_health_failure = 0;
+            changed = true;
+        }
+        changed
+    }
+}
+
+fn update_latency_ewma(current: u64, sample: Duration) -> u64 {
+    let sample = sample.as_micros() as u64;
+    if current == 0 {
+        return sample.max(1);
+    }
+    ((1.0 - LATENCY_EWMA_ALPHA) * current as f64 + LATENCY_EWMA_ALPHA * sample as f64) as u64
+}
+
+pub fn calc_target_id_by_endpoint(endpoint: String) -> String {
+    let digest = md5::compute(endpoint.as_str());
+    format!("{:x}", digest).to_string()
+}
+
+// let the balancer know the current set of targets
+fn update_balancer_targets(targets_info: &HashMap<String, Target>) {
+    SERVER_INFO
+        .deref()
+        .balancer
+        .update_targets(targets_info.keys().cloned().collect());
+}
+
+pub async fn init_targets_from_config() {
+    let mut targets_info = SERVER_INFO.deref().targets_info.lock().await;
+    for target_config in SERVER_INFO.deref().config().lb_targets.iter() {
+        let target = Target::from_config(target_config);
+
+        targets_info.insert(
             calc_target_id_by_endpoint(target.clone().target_endpoint),
             target,
         );
     }
+    update_balancer_targets(&targets_info);
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TargetSyncResult {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+// bring the targets in line with the configured ones, kept targets keep their runtime state
+pub async fn sync_targets_from_config(target_configs: &[TargetConfig]) -> TargetSyncResult {
+    let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let mut result = TargetSyncResult::default();
+    let mut targets_info = SERVER_INFO.deref().targets_info.lock().await;
+
+    let mut configured = HashSet::new();
+    for target_config in target_configs.iter() {
+        let target_id = calc_target_id_by_endpoint(target_config.target_endpoint.clone());
+        configured.insert(target_id.clone());
+        match targets_info.get_mut(&target_id) {
+            Some(target) => {
+                if target.update_from_config(target_config) {
+                    result.updated.push(target_id);
+                }
+            }
+            None => {
+                let mut target = Target::from_config(target_config);
+                target.target_slow_start_begin = now;
+                targets_info.insert(target_id.clone(), target);
+                result.added.push(target_id);
+            }
+        }
+    }
+
+    targets_info.retain(|target_id, _| {
+        if configured.contains(target_id) {
+            return true;
+        }
+        result.removed.push(target_id.clone());
+        false
+    });
+    update_balancer_targets(&targets_info);
+    result
 }
 
 #[derive(Debug, Clone)]
 pub struct TargetDump {
     pub target: Target,
     pub target_conn_count: u32,
+    pub target_traffic_speed: u64,
 }
 
 impl TargetDump {
+    #[allow(dead_code)]
     pub fn new(
         target_endpoint: String,
         target_max_conn: u32,
@@ -75,6 +291,7 @@ impl TargetDump {
         target_timeout: u32,
         target_active: bool,
         target_status: bool,
+        target_weight: u32,
     ) -> TargetDump {
         TargetDump {
             target: Target::new(
@@ -83,28 +300,25 @@ impl TargetDump {
                 target_timeout,
                 target_active,
                 target_status,
+                target_weight,
+                false,
             ),
             target_conn_count,
+            target_traffic_speed: 0,
         }
     }
 }
 
 pub async fn dump_targets(order: TargetDumpOrder) -> Vec<TargetDump> {
     let mut target_dump_vec = Vec::<TargetDump>::new();
-    for (_, v) in SERVER_INFO.deref().targets_info.lock().await.iter() {
-        let target_conn_count = get_target_conn_count_by_target_id(calc_target_id_by_endpoint(
-            v.target_endpoint.clone(),
-        ))
-        .await;
-        let target_dump = TargetDump::new(
-            v.target_endpoint.clone(),
-            v.target_max_conn,
-            target_conn_count,
-            v.target_timeout,
-            v.target_active,
-            v.target_status,
-        );
-        target_dump_vec.push(target_dump);
+    let targets_stat = get_targets_tunnel_stat().await;
+    for (k, v) in SERVER_INFO.deref().targets_info.lock().await.iter() {
+        let target_stat = targets_stat.get(k).cloned().unwrap_or_default();
+        target_dump_vec.push(TargetDump {
+            target: v.clone(),
+            target_conn_count: target_stat.conn_count,
+            target_traffic_speed: target_stat.traffic_speed_1m,
+        });
     }
     match order {
         TargetDumpOrder::AscOrder => {
@@ -118,6 +332,274 @@ pub async fn dump_targets(order: TargetDumpOrder) -> Vec<TargetDump> {
     target_dump_vec
 }
 
+// backup targets only take connections when no primary target is eligible, the flag
+// tells if the backup tier was chosen
+pub fn select_target_tier(targets: Vec<TargetDump>) -> (Vec<TargetDump>, bool) {
+    let (backups, primaries): (Vec<TargetDump>, Vec<TargetDump>) =
+        targets.into_iter().partition(|t| t.target.target_backup);
+    if primaries.is_empty() && !backups.is_empty() {
+        (backups, true)
+    } else {
+        (primaries, false)
+    }
+}
+
+pub async fn set_target_weight(target_id: String, target_weight: u32) -> bool {
+    match SERVER_INFO
+        .deref()
+        .targets_info
+        .lock()
+        .await
+        .get_mut(&target_id)
+    {
+        Some(target) => {
+            target.target_weight = target_weight;
+            true
+        }
+        None => false,
+    }
+}
+
+pub async fn update_target_connect_latency(target_id: &str, latency: Duration) {
+    if let Some(target) = SERVER_INFO
+        .deref()
+        .targets_info
+        .lock()
+        .await
+        .get_mut(target_id)
+    {
+        target.target_connect_latency = update_latency_ewma(target.target_connect_latency, latency);
+    }
+}
+
+pub async fn update_target_first_byte_latency(target_id: &str, latency: Duration) {
+    if let Some(target) = SERVER_INFO
+        .deref()
+        .targets_info
+        .lock()
+        .await
+        .get_mut(target_id)
+    {
+        target.target_first_byte_latency =
+            update_latency_ewma(target.target_first_byte_latency, latency);
+    }
+}
+
+pub async fn add_target(target_config: &TargetConfig) -> Result<String, String> {
+    target_config.check()?;
+    SERVER_INFO
+        .deref()
+        .config()
+        .lb_node
+        .check_target_family(&target_config.target_endpoint)?;
+    let target_id = calc_target_id_by_endpoint(target_config.target_endpoint.clone());
+    let mut targets_info = SERVER_INFO.deref().targets_info.lock().await;
+    if targets_info.contains_key(&target_id) {
+        return Err(format!(
+            "Target [{}] already exists",
+            target_config.target_endpoint
+        ));
+    }
+    let mut target = Target::from_config(target_config);
+    // a new target ramps up like a recovered one
+    target.target_slow_start_begin = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    targets_info.insert(target_id.clone(), target);
+    update_balancer_targets(&targets_info);
+    Ok(target_id)
+}
+
+pub async fn remove_target(target_id: &str) -> Option<Target> {
+    let mut targets_info = SERVER_INFO.deref().targets_info.lock().await;
+    let target = targets_info.remove(target_id);
+    update_balancer_targets(&targets_info);
+    target
+}
+
+pub async fn update_target(
+    target_id: &str,
+    target_max_conn: Option<u32>,
+    target_timeout: Option<u32>,
+    target_active: Option<bool>,
+) -> Result<(), String> {
+    if target_max_conn == Some(0) {
+        return Err("Target max conn must be positive".to_string());
+    }
+    let mut targets_info = SERVER_INFO.deref().targets_info.lock().await;
+    let target = match targets_info.get_mut(target_id) {
+        Some(target) => target,
+        None => return Err("Target not found".to_string()),
+    };
+    if let Some(target_max_conn) = target_max_conn {
+        target.target_max_conn = target_max_conn;
+    }
+    if let Some(target_timeout) = target_timeout {
+        target.target_timeout = target_timeout;
+    }
+    if let Some(target_active) = target_active {
+        if target_active && !target.target_active {
+            target.target_slow_start_begin = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        }
+        target.target_active = target_active;
+    }
+    Ok(())
+}
+
+pub async fn drain_target(target_id: &str, deadline_secs: u32) -> bool {
+    let drain_deadline = if deadline_secs > 0 {
+        Utc::now().timestamp_nanos_opt().unwrap_or_default() + deadline_secs as i64 * 1_000_000_000
+    } else {
+        0
+    };
+    match SERVER_INFO
+        .deref()
+        .targets_info
+        .lock()
+        .await
+        .get_mut(target_id)
+    {
+        Some(target) => {
+            target.target_draining = true;
+            target.target_drain_deadline = drain_deadline;
+        }
+        None => return false,
+    }
+
+    if deadline_secs > 0 {
+        let target_id = target_id.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(deadline_secs as u64)).await;
+            // the drain may have been cancelled or restarted in the meantime
+            let still_draining = match SERVER_INFO
+                .deref()
+                .targets_info
+                .lock()
+                .await
+                .get(&target_id)
+            {
+                Some(target) => {
+                    target.target_draining && target.target_drain_deadline == drain_deadline
+                }
+                None => false,
+            };
+            if !still_draining {
+                return;
+            }
+            let closed = close_tunnels(
+                |_, target_info| target_info.target_id == target_id,
+                "drain deadline reached",
+            )
+            .await;
+            if !closed.is_empty() {
+                warn!(
+                    "drain deadline of target |{}| reached, force close {} tunnels",
+                    target_id,
+                    closed.len()
+                );
+            }
+        });
+    }
+    true
+}
+
+pub async fn undrain_target(target_id: &str) -> bool {
+    match SERVER_INFO
+        .deref()
+        .targets_info
+        .lock()
+        .await
+        .get_mut(target_id)
+    {
+        Some(target) => {
+            target.target_draining = false;
+            target.target_drain_deadline = 0;
+            true
+        }
+        None => false,
+    }
+}
+
+// None if the target does not exist
+pub async fn get_target_health_check(target_id: &str) -> Option<Option<HealthCheckConfig>> {
+    SERVER_INFO
+        .deref()
+        .targets_info
+        .lock()
+        .await
+        .get(target_id)
+        .map(|target| target.target_health_check.clone())
+}
+
+pub async fn set_target_health_check(
+    target_id: String,
+    health_check: Option<HealthCheckConfig>,
+) -> bool {
+    match SERVER_INFO
+        .deref()
+        .targets_info
+        .lock()
+        .await
+        .get_mut(&target_id)
+    {
+        Some(target) => {
+            // a target without health check is always considered healthy
+            if health_check.is_none() {
+                target.target_status = true;
+            }
+            target.target_health_check = health_check;
+            target.target_health_success = 0;
+            target.target_health_failure = 0;
+            true
+        }
+        None => false,
+    }
+}
+
+#[test]
+fn test_update_latency_ewma() {
+    let latency = update_latency_ewma(0, Duration::from_millis(10));
+    assert_eq!(latency, 10000);
+    let latency = update_latency_ewma(latency, Duration::from_millis(20));
+    assert_eq!(latency, 12000);
+}
+
+#[test]
+fn test_select_target_tier() {
+    let mut backup = TargetDump::new("127.0.0.1:1082".to_string(), 100, 0, 60, true, true, 1);
+    backup.target.target_backup = true;
+    let primary = TargetDump::new("127.0.0.1:1081".to_string(), 100, 0, 60, true, true, 1);
+
+    let (tier, is_backup) = select_target_tier(vec![primary, backup.clone()]);
+    assert!(!is_backup);
+    assert_eq!(tier.len(), 1);
+    assert_eq!(tier[0].target.target_endpoint, "127.0.0.1:1081");
+
+    let (tier, is_backup) = select_target_tier(vec![backup]);
+    assert!(is_backup);
+    assert_eq!(tier[0].target.target_endpoint, "127.0.0.1:1082");
+
+    // nothing eligible in either tier is not a fail over
+    let (tier, is_backup) = select_target_tier(vec![]);
+    assert!(!is_backup);
+    assert!(tier.is_empty());
+}
+
+#[test]
+fn test_slow_start() {
+    let mut target = Target::new("127.0.0.1:1081".to_string(), 100, 60, true, true, 10, false);
+    let begin = 1_000_000_000_000;
+    assert_eq!(target.slow_start_factor(begin, 60), 1.0);
+
+    target.target_slow_start_begin = begin;
+    assert_eq!(target.slow_start_factor(begin, 0), 1.0);
+    assert_eq!(target.slow_start_factor(begin, 60), SLOW_START_MIN_FACTOR);
+    assert_eq!(target.slow_start_factor(begin + 30_000_000_000, 60), 0.5);
+    assert_eq!(target.slow_start_factor(begin + 90_000_000_000, 60), 1.0);
+
+    target.apply_slow_start(begin + 30_000_000_000, 60);
+    assert_eq!(target.target_max_conn, 50);
+    assert_eq!(target.target_weight, 5);
+}
+
 #[test]
 fn test_calc_target_id() {
     let target_id = calc_target_id_by_endpoint("127.0.0.1:1080".to_string());
@@ -0,0 +1,196 @@
+// End to end tests of the management api.
+mod common;
+
+use common::{Proxy, ProxyOptions};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+#[tokio::test]
+async fn test_set_target_health_check_keeps_current_check() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = listener.local_addr().unwrap();
+    let proxy = Proxy::start(
+        "set_target_health_check",
+        target_addr,
+        ProxyOptions {
+            target_extra: r#", "target_health_check": {"check_type": "http",
+                "http_path": "/health", "http_expect_status": [200, 204]}"#
+                .to_string(),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let targets = proxy.api_get("/api/get_targets_info").await;
+    let target_id = targets[0]["target_id"].as_str().unwrap().to_string();
+    let result = proxy
+        .api_get(&format!(
+            "/api/set_target_health_check?target_id={}&interval_ms=1000",
+            target_id
+        ))
+        .await;
+    assert_eq!(result, true);
+
+    // only the interval changed, the http check is still in place
+    let targets = proxy.api_get("/api/get_targets_info").await;
+    let health_check = &targets[0]["health_check"];
+    assert_eq!(health_check["check_type"], "http");
+    assert_eq!(health_check["http_path"], "/health");
+    assert_eq!(
+        health_check["http_expect_status"],
+        serde_json::json!([200, 204])
+    );
+    assert_eq!(health_check["interval_ms"], 1000);
+}
+
+#[tokio::test]
+async fn test_add_target_checks_local_endpoint_family() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = listener.local_addr().unwrap();
+    let proxy = Proxy::start(
+        "add_target_family",
+        target_addr,
+        ProxyOptions {
+            local_endpoints: vec!["127.0.0.1:0"],
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // no ipv6 local endpoint to connect an ipv6 target from
+    let (status, body) = proxy
+        .api_request("/api/add_target?target_endpoint=%5B%3A%3A1%5D%3A8080")
+        .await;
+    assert_eq!(status, 422);
+    assert!(body.contains("address family"), "{}", body);
+
+    proxy
+        .api_get("/api/add_target?target_endpoint=127.0.0.1%3A8080")
+        .await;
+    assert_eq!(
+        proxy
+            .api_get("/api/get_targets_info")
+            .await
+            .as_array()
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+// an echo backend, each connection reports on the channel once the proxy closed it
+async fn start_echo_backend() -> (SocketAddr, mpsc::UnboundedReceiver<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (closed_tx, closed_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let closed_tx = closed_tx.clone();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+                let _ = closed_tx.send(());
+            });
+        }
+    });
+    (addr, closed_rx)
+}
+
+// a client with its tunnel fully set up
+async fn open_tunnel(proxy: &Proxy) -> TcpStream {
+    let mut client = TcpStream::connect(proxy.listen).await.unwrap();
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await.unwrap();
+    client
+}
+
+// both the client and the backend side of the tunnel are closed by the proxy
+async fn assert_tunnel_closed(
+    client: &mut TcpStream,
+    backend_closed: &mut mpsc::UnboundedReceiver<()>,
+) {
+    let mut rest = Vec::new();
+    let read = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+        .await
+        .expect("client side was not closed");
+    assert!(read.is_err() || rest.is_empty(), "{:?}", rest);
+    tokio::time::timeout(Duration::from_secs(5), backend_closed.recv())
+        .await
+        .expect("backend side was not closed")
+        .unwrap();
+}
+
+async fn kill_one_tunnel(proxy: &Proxy, query: &str) {
+    let killed = proxy.api_get(&format!("/api/kill_tunnel?{}", query)).await;
+    assert_eq!(killed.as_array().unwrap().len(), 1, "{}", killed);
+}
+
+#[tokio::test]
+async fn test_kill_tunnel() {
+    let (target_addr, mut backend_closed) = start_echo_backend().await;
+    let proxy = Proxy::start("kill_tunnel", target_addr, Default::default()).await;
+    let mut client = open_tunnel(&proxy).await;
+    let tunnels = proxy.api_get("/api/get_tunnel_info").await;
+    let tunnel_id = tunnels[0]["tunnel_id"].as_str().unwrap().to_string();
+    kill_one_tunnel(&proxy, &format!("tunnel_id={}", tunnel_id)).await;
+    assert_tunnel_closed(&mut client, &mut backend_closed).await;
+
+    let mut client = open_tunnel(&proxy).await;
+    kill_one_tunnel(&proxy, "client_ip=127.0.0.1").await;
+    assert_tunnel_closed(&mut client, &mut backend_closed).await;
+
+    let mut client = open_tunnel(&proxy).await;
+    let tunnels = proxy.api_get("/api/get_tunnel_info").await;
+    let target_id = tunnels[0]["target_connection"]["target_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    kill_one_tunnel(&proxy, &format!("target_id={}", target_id)).await;
+    assert_tunnel_closed(&mut client, &mut backend_closed).await;
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(proxy.tunnel_count().await, 0);
+    assert_eq!(proxy.tunnel_close_count("killed by api").await, 3);
+}
+
+#[tokio::test]
+async fn test_drain_target_deadline() {
+    let (target_addr, mut backend_closed) = start_echo_backend().await;
+    let proxy = Proxy::start("drain_target", target_addr, Default::default()).await;
+    let mut client = open_tunnel(&proxy).await;
+    let targets = proxy.api_get("/api/get_targets_info").await;
+    let target_id = targets[0]["target_id"].as_str().unwrap().to_string();
+
+    proxy
+        .api_get(&format!(
+            "/api/drain_target?target_id={}&deadline_secs=1",
+            target_id
+        ))
+        .await;
+    let status = proxy
+        .api_get(&format!("/api/drain_status?target_id={}", target_id))
+        .await;
+    assert_eq!(status["draining"], true);
+    assert_eq!(status["remaining_tunnels"], 1);
+    assert_eq!(status["drained"], false);
+
+    // the live tunnel keeps working until the deadline force closes it
+    client.write_all(b"pong").await.unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+    assert_tunnel_closed(&mut client, &mut backend_closed).await;
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let status = proxy
+        .api_get(&format!("/api/drain_status?target_id={}", target_id))
+        .await;
+    assert_eq!(status["remaining_tunnels"], 0);
+    assert_eq!(status["drained"], true);
+    assert_eq!(proxy.tunnel_close_count("drain deadline reached").await, 1);
+}
@@ -0,0 +1,198 @@
+// Runs the proxy binary with a generated config for the end to end tests.
+#![allow(dead_code)]
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub fn free_local_addr() -> SocketAddr {
+    free_addr("127.0.0.1")
+}
+
+pub fn free_addr(ip: &str) -> SocketAddr {
+    std::net::TcpListener::bind(SocketAddr::new(ip.parse().unwrap(), 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+pub struct ProxyOptions {
+    pub listen: Option<SocketAddr>,
+    pub timeout: u32,
+    pub local_endpoints: Vec<&'static str>,
+    // appended to the lb_node and lb_targets[0] sections of the config
+    pub node_extra: String,
+    pub target_extra: String,
+    // soft and hard limit of open files for the proxy process
+    pub max_open_files: Option<u64>,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> ProxyOptions {
+        ProxyOptions {
+            listen: None,
+            timeout: 10,
+            local_endpoints: Vec::new(),
+            node_extra: String::new(),
+            target_extra: String::new(),
+            max_open_files: None,
+        }
+    }
+}
+
+// the proxy process, killed when the test ends
+pub struct Proxy {
+    child: Child,
+    pub listen: SocketAddr,
+    pub api_listen: SocketAddr,
+    work_dir: PathBuf,
+}
+
+impl Proxy {
+    pub async fn start(name: &str, target_addr: SocketAddr, options: ProxyOptions) -> Proxy {
+        let proxy = Proxy::spawn(name, target_addr, options);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(proxy.api_listen).await.is_err() {
+            assert!(Instant::now() < deadline, "proxy did not start listening");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        proxy
+    }
+
+    // run the proxy and wait for it to exit on its own
+    pub fn run_until_exit(
+        name: &str,
+        target_addr: SocketAddr,
+        options: ProxyOptions,
+    ) -> ExitStatus {
+        let mut proxy = Proxy::spawn(name, target_addr, options);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(status) = proxy.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "proxy did not exit");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn spawn(name: &str, target_addr: SocketAddr, options: ProxyOptions) -> Proxy {
+        let work_dir =
+            std::env::temp_dir().join(format!("tcp_lb_test_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&work_dir).unwrap();
+        let config_path = work_dir.join("lb-config.json");
+        let listen = options.listen.unwrap_or_else(free_local_addr);
+        let api_listen = free_local_addr();
+        let config = format!(
+            r#"{{
+                "lb_log": {{"log_set_level": "info"}},
+                "lb_node": {{"listen": "{}", "max_conn": 100, "timeout": {},
+                    "enable_local_endpoints": {}, "local_endpoints": {}{}}},
+                "lb_targets": [{{"target_endpoint": "{}", "target_max_conn": 100,
+                    "target_timeout": 10, "target_active": true{}}}],
+                "lb_api": {{"listen": "{}"}}
+            }}"#,
+            listen,
+            options.timeout,
+            !options.local_endpoints.is_empty(),
+            serde_json::to_string(&options.local_endpoints).unwrap(),
+            options.node_extra,
+            target_addr,
+            options.target_extra,
+            api_listen
+        );
+        std::fs::write(&config_path, config).unwrap();
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_tcp_lb_rs"));
+        command
+            .arg("--config")
+            .arg(&config_path)
+            .arg("--log-dir")
+            .arg(work_dir.join("log"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some(max_open_files) = options.max_open_files {
+            limit_open_files(&mut command, max_open_files);
+        }
+        let child = command.spawn().expect("Failure starting the proxy binary");
+        Proxy {
+            child,
+            listen,
+            api_listen,
+            work_dir,
+        }
+    }
+
+    // the status code and body of an api call
+    pub async fn api_request(&self, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(self.api_listen).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: lb\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        let status = resp.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = resp.split("\r\n\r\n").nth(1).unwrap().to_string();
+        (status, body)
+    }
+
+    // the result of a successful api call
+    pub async fn api_get(&self, path: &str) -> serde_json::Value {
+        let (status, body) = self.api_request(path).await;
+        assert_eq!(status, 200, "{}", body);
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()["result"].clone()
+    }
+
+    // whether the process is still alive
+    pub fn is_running(&mut self) -> bool {
+        self.child.try_wait().unwrap().is_none()
+    }
+
+    pub async fn tunnel_count(&self) -> usize {
+        self.api_get("/api/get_tunnel_info")
+            .await
+            .as_array()
+            .unwrap()
+            .len()
+    }
+
+    pub async fn tunnel_close_count(&self, reason: &str) -> u64 {
+        self.api_get("/api/get_node_info").await["tunnel_close_reasons"][reason]
+            .as_u64()
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.work_dir);
+    }
+}
+
+#[cfg(unix)]
+fn limit_open_files(command: &mut Command, max_open_files: u64) {
+    use std::os::unix::process::CommandExt;
+
+    let limit = libc::rlimit {
+        rlim_cur: max_open_files as libc::rlim_t,
+        rlim_max: max_open_files as libc::rlim_t,
+    };
+    // the hard limit is lowered too, the proxy raises the soft limit up to it at start
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn limit_open_files(_command: &mut Command, _max_open_files: u64) {}
@@ -0,0 +1,82 @@
+// End to end tests of when the proxy process exits: a failed startup stops it,
+// errors while running must not.
+mod common;
+
+use common::{free_local_addr, Proxy, ProxyOptions};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[test]
+fn test_exit_when_listen_endpoint_is_taken() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let status = Proxy::run_until_exit(
+        "listen_taken",
+        free_local_addr(),
+        ProxyOptions {
+            listen: Some(taken.local_addr().unwrap()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(status.code(), Some(1));
+}
+
+// a backend that echoes everything back
+async fn start_echo_backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn echo(listen: SocketAddr) -> bool {
+    let mut client = match TcpStream::connect(listen).await {
+        Ok(client) => client,
+        Err(_) => return false,
+    };
+    let mut buf = [0u8; 4];
+    client.write_all(b"ping").await.is_ok()
+        && matches!(
+            tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf)).await,
+            Ok(Ok(_))
+        )
+        && &buf == b"ping"
+}
+
+#[tokio::test]
+async fn test_keep_running_when_out_of_fds() {
+    let target_addr = start_echo_backend().await;
+    let mut proxy = Proxy::start(
+        "out_of_fds",
+        target_addr,
+        ProxyOptions {
+            max_open_files: Some(64),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(echo(proxy.listen).await);
+
+    // every tunnel takes two fds, far more clients than the limit allows
+    let mut clients = Vec::new();
+    for _ in 0..60 {
+        clients.push(TcpStream::connect(proxy.listen).await.unwrap());
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(proxy.is_running());
+
+    // once the clients are gone the proxy serves again
+    drop(clients);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(proxy.is_running());
+    assert!(echo(proxy.listen).await);
+}
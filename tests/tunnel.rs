@@ -0,0 +1,306 @@
+// End to end tests of the tunnel data path, each test runs the proxy binary
+// against its own local backend.
+mod common;
+
+use common::{free_addr, Proxy, ProxyOptions};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+// a backend that answers only after the client finished sending, like a
+// request that is terminated by the half close
+async fn start_reply_after_eof_backend(delay: Duration) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                stream.read_to_end(&mut request).await.unwrap();
+                tokio::time::sleep(delay).await;
+                stream.write_all(&request).await.unwrap();
+            });
+        }
+    });
+    addr
+}
+
+async fn client_half_close(forwarding: &str) {
+    let target_addr = start_reply_after_eof_backend(Duration::from_millis(500)).await;
+    let proxy = Proxy::start(
+        &format!("client_half_close_{}", forwarding),
+        target_addr,
+        ProxyOptions {
+            node_extra: format!(r#", "forwarding": "{}""#, forwarding),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let request: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut client = TcpStream::connect(proxy.listen).await.unwrap();
+    client.write_all(&request).await.unwrap();
+    client.shutdown().await.unwrap();
+
+    // the tunnel stays up while the response is pending
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(proxy.tunnel_count().await, 1);
+
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+        .await
+        .expect("response timeout")
+        .unwrap();
+    assert_eq!(response, request);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(proxy.tunnel_count().await, 0);
+}
+
+#[tokio::test]
+async fn test_client_half_close() {
+    client_half_close("buffered").await;
+}
+
+#[tokio::test]
+async fn test_client_half_close_splice() {
+    client_half_close("splice").await;
+}
+
+#[tokio::test]
+async fn test_target_half_close() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = listener.local_addr().unwrap();
+    let (received_tx, received_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        // greet, close the sending side and keep reading until the client is done
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        let _ = received_tx.send(received);
+    });
+    let proxy = Proxy::start("target_half_close", target_addr, Default::default()).await;
+
+    let mut client = TcpStream::connect(proxy.listen).await.unwrap();
+    let mut greeting = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut greeting))
+        .await
+        .expect("greeting timeout")
+        .unwrap();
+    assert_eq!(greeting, b"hello");
+
+    client.write_all(b"bye").await.unwrap();
+    client.shutdown().await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), received_rx)
+        .await
+        .expect("backend timeout")
+        .unwrap();
+    assert_eq!(received, b"bye");
+}
+
+// a backend that sends a tick every interval and never reads
+async fn start_ticking_backend(interval: Duration) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                while stream.write_all(b"tick").await.is_ok() {
+                    tokio::time::sleep(interval).await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+// read until the proxy closes the connection, return the bytes and how long it took
+async fn read_until_closed(client: &mut TcpStream) -> (usize, Duration) {
+    let start = Instant::now();
+    let mut received = 0;
+    let mut buf = [0u8; 1024];
+    loop {
+        match tokio::time::timeout(Duration::from_secs(10), client.read(&mut buf))
+            .await
+            .expect("tunnel was not closed")
+        {
+            Ok(0) | Err(_) => return (received, start.elapsed()),
+            Ok(n) => received += n,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_idle_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        // accept and stay silent
+        let (_stream, _) = listener.accept().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    });
+    let proxy = Proxy::start(
+        "idle_timeout",
+        target_addr,
+        ProxyOptions {
+            timeout: 1,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let mut client = TcpStream::connect(proxy.listen).await.unwrap();
+    let (received, elapsed) = read_until_closed(&mut client).await;
+    assert_eq!(received, 0);
+    assert!(elapsed >= Duration::from_millis(800), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    assert_eq!(proxy.tunnel_close_count("idle timeout").await, 1);
+}
+
+#[tokio::test]
+async fn test_one_busy_direction_is_not_idle() {
+    let target_addr = start_ticking_backend(Duration::from_millis(200)).await;
+    let proxy = Proxy::start(
+        "busy_direction",
+        target_addr,
+        ProxyOptions {
+            timeout: 1,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // the client never sends, the ticks alone keep the tunnel open
+    let mut client = TcpStream::connect(proxy.listen).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(proxy.tunnel_count().await, 1);
+    let mut buf = [0u8; 1024];
+    assert!(client.read(&mut buf).await.unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_max_tunnel_lifetime() {
+    let target_addr = start_ticking_backend(Duration::from_millis(100)).await;
+    let proxy = Proxy::start(
+        "max_lifetime",
+        target_addr,
+        ProxyOptions {
+            node_extra: r#", "max_tunnel_lifetime_secs": 1"#.to_string(),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let mut client = TcpStream::connect(proxy.listen).await.unwrap();
+    let (received, elapsed) = read_until_closed(&mut client).await;
+    assert!(received > 0);
+    assert!(elapsed >= Duration::from_millis(800), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    assert_eq!(proxy.tunnel_close_count("max lifetime reached").await, 1);
+}
+
+// a backend that echoes everything back
+async fn start_echo_backend(ip: &str) -> SocketAddr {
+    let listener = TcpListener::bind(SocketAddr::new(ip.parse().unwrap(), 0))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn assert_echo(listen: SocketAddr) {
+    let mut client = TcpStream::connect(listen).await.unwrap();
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+        .await
+        .expect("echo timeout")
+        .unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test]
+async fn test_ipv6_listener_and_target() {
+    let target_addr = start_echo_backend("::1").await;
+    let proxy = Proxy::start(
+        "ipv6",
+        target_addr,
+        ProxyOptions {
+            listen: Some(free_addr("::1")),
+            local_endpoints: vec!["127.0.0.1:0", "[::1]:0"],
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let mut client = TcpStream::connect(proxy.listen).await.unwrap();
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // the target side is bound to the ipv6 local endpoint
+    let tunnels = proxy.api_get("/api/get_tunnel_info").await;
+    let target_local = tunnels[0]["target_connection"]["local_endpoint"]
+        .as_str()
+        .unwrap()
+        .parse::<SocketAddr>()
+        .unwrap();
+    assert_eq!(
+        target_local.ip(),
+        "::1".parse::<std::net::IpAddr>().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_ipv4_client_to_ipv6_target() {
+    let target_addr = start_echo_backend("::1").await;
+    let proxy = Proxy::start("ipv4_to_ipv6", target_addr, Default::default()).await;
+    assert_echo(proxy.listen).await;
+}
+
+#[tokio::test]
+async fn test_dual_stack_listener() {
+    let target_addr = start_echo_backend("127.0.0.1").await;
+    let port = free_addr("::").port();
+    let proxy = Proxy::start(
+        "dual_stack",
+        target_addr,
+        ProxyOptions {
+            listen: Some(SocketAddr::new("::".parse().unwrap(), port)),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    assert_echo(SocketAddr::new("::1".parse().unwrap(), port)).await;
+    assert_echo(SocketAddr::new("127.0.0.1".parse().unwrap(), port)).await;
+
+    // ipv4 clients are reported with their plain ipv4 address
+    let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let client_addr = client.local_addr().unwrap().to_string();
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await.unwrap();
+    let tunnels = proxy.api_get("/api/get_tunnel_info").await;
+    assert!(tunnels
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|t| t["node_connection"]["remote_endpoint"] == client_addr.as_str()));
+}